@@ -45,11 +45,30 @@ fn criterion_benchmark(c: &mut Criterion) {
             black_box(&json).to_string();
         })
     });
+    c.bench_function("binary.from_json_str", |b| {
+        b.iter(|| from_json_str(black_box(&json_string)))
+    });
+    c.bench_function("binary.to_json_string", |b| {
+        b.iter(|| to_json_string(black_box(&serialized), 0))
+    });
     c.bench_function("binary.seek", |b| {
         b.iter(|| {
             black_box({
-                let k = seek_key(&serialized, Some(0), String::from("dependencies")).unwrap();
-                let s = seek_key(&serialized, Some(k), String::from("varint")).unwrap();
+                let k = seek_key(&serialized, Some(0), String::from("dependencies"))
+                    .unwrap()
+                    .unwrap();
+                let s = seek_key(&serialized, Some(k), String::from("varint"))
+                    .unwrap()
+                    .unwrap();
+                decode_rec(&serialized, s)
+            })
+        })
+    });
+    let path = SeekPath::compile(&["dependencies".into(), "varint".into()]);
+    c.bench_function("binary.seek_compiled", |b| {
+        b.iter(|| {
+            black_box({
+                let s = path.seek(&serialized, 0).unwrap().unwrap();
                 decode_rec(&serialized, s)
             })
         })
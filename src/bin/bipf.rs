@@ -0,0 +1,241 @@
+use bipf_neon::*;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::exit;
+
+const USAGE: &str = "usage: bipf <command> [file]
+
+Reads from file, or stdin when it is missing or `-`.
+
+commands:
+  encode [file]              JSON to BIPF
+  decode [--pretty] [file]   BIPF to JSON
+  seek <path> [file]         print the value at <path>, either dotted (`a.b.0`)
+                             or a JSON array (`[\"a\", \"b\", 0]`)
+  validate [file]            check the structure, report the first error
+  explain [file]             annotated dump of every value";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match parse_args(&args) {
+        Some(Command::Encode(file)) => input(file).and_then(encode),
+        Some(Command::Decode { pretty, file }) => {
+            read_all(file).and_then(|buf| print_json(&buf, 0, pretty))
+        }
+        Some(Command::Seek { path, file }) => read_all(file).and_then(|buf| seek(&buf, path)),
+        Some(Command::Validate(file)) => read_all(file).and_then(|buf| check(&buf)),
+        Some(Command::Explain(file)) => read_all(file).and_then(|buf| dump(&buf)),
+        None => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("bipf: {}", e);
+        exit(1);
+    }
+}
+
+/// A command line, with the file arguments left for `input` to check.
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Encode(&'a [&'a str]),
+    Decode { pretty: bool, file: &'a [&'a str] },
+    Seek { path: &'a str, file: &'a [&'a str] },
+    Validate(&'a [&'a str]),
+    Explain(&'a [&'a str]),
+}
+
+/// `--pretty` is only a flag right after `decode`, anywhere else it is an argument.
+fn parse_args<'a>(args: &'a [&'a str]) -> Option<Command<'a>> {
+    match args {
+        ["encode", file @ ..] => Some(Command::Encode(file)),
+        ["decode", "--pretty", file @ ..] => Some(Command::Decode { pretty: true, file }),
+        ["decode", file @ ..] => Some(Command::Decode {
+            pretty: false,
+            file,
+        }),
+        ["seek", path, file @ ..] => Some(Command::Seek { path, file }),
+        ["validate", file @ ..] => Some(Command::Validate(file)),
+        ["explain", file @ ..] => Some(Command::Explain(file)),
+        _ => None,
+    }
+}
+
+fn input(file: &[&str]) -> Result<Box<dyn Read>, BipfError> {
+    match file {
+        [] | ["-"] => Ok(Box::new(io::stdin())),
+        [path] => Ok(Box::new(File::open(path)?)),
+        _ => Err(BipfError::Message(String::from("too many arguments"))),
+    }
+}
+
+fn read_all(file: &[&str]) -> Result<Vec<u8>, BipfError> {
+    let mut buf = Vec::new();
+    input(file)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn encode(reader: Box<dyn Read>) -> Result<(), BipfError> {
+    let buf = from_json_reader(reader)?;
+    io::stdout().write_all(&buf)?;
+    Ok(())
+}
+
+fn print_json(buf: &[u8], start: usize, pretty: bool) -> Result<(), BipfError> {
+    let style = if pretty {
+        JsonStyle::Pretty
+    } else {
+        JsonStyle::Compact
+    };
+    let mut out = io::stdout().lock();
+    to_json_writer_with(buf, start, &mut out, style, &JsonOptions::default())?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn seek(buf: &[u8], path: &str) -> Result<(), BipfError> {
+    let segments = parse_path(path)?;
+    let segments: Vec<PathSegment> = segments.iter().map(PathSegment::from).collect();
+
+    match seek_segments(buf, 0, &segments)? {
+        Some(start) => print_json(buf, start, false),
+        None => Err(BipfError::Message(format!("{} not found", path))),
+    }
+}
+
+/// A path segment as typed on the command line.
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl<'a> From<&'a Segment> for PathSegment<'a> {
+    fn from(segment: &'a Segment) -> Self {
+        match segment {
+            Segment::Key(k) => PathSegment::Key(k),
+            Segment::Index(i) => PathSegment::Index(*i),
+        }
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, BipfError> {
+    if !path.starts_with('[') {
+        return Ok(path
+            .split('.')
+            .filter(|s| !s.is_empty())
+            .map(|s| match s.parse() {
+                Ok(i) => Segment::Index(i),
+                Err(_) => Segment::Key(String::from(s)),
+            })
+            .collect());
+    }
+
+    let invalid = || BipfError::Message(format!("invalid path {}", path));
+    match serde_json::from_str(path).map_err(|_| invalid())? {
+        serde_json::Value::Array(v) => v
+            .into_iter()
+            .map(|s| match s {
+                serde_json::Value::String(k) => Ok(Segment::Key(k)),
+                serde_json::Value::Number(n) => match n.as_u64() {
+                    Some(i) => Ok(Segment::Index(i as usize)),
+                    None => Err(invalid()),
+                },
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+fn check(buf: &[u8]) -> Result<(), BipfError> {
+    let report = validate(buf)?;
+    println!("ok: {} values, depth {}", report.values, report.depth);
+    Ok(())
+}
+
+fn dump(buf: &[u8]) -> Result<(), BipfError> {
+    io::stdout().write_all(explain(buf, 0).as_bytes())?;
+    // The dump ends with the error, if any, but the exit status should tell too
+    explain_nodes(buf, 0)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&["encode"]), Some(Command::Encode(&[])));
+        assert_eq!(
+            parse_args(&["decode", "--pretty", "-"]),
+            Some(Command::Decode {
+                pretty: true,
+                file: &["-"]
+            })
+        );
+        assert_eq!(
+            parse_args(&["decode", "a.bipf"]),
+            Some(Command::Decode {
+                pretty: false,
+                file: &["a.bipf"]
+            })
+        );
+        // Anywhere but right after decode, --pretty is an argument like any other
+        assert_eq!(
+            parse_args(&["seek", "--pretty", "a.bipf"]),
+            Some(Command::Seek {
+                path: "--pretty",
+                file: &["a.bipf"]
+            })
+        );
+        assert_eq!(
+            parse_args(&["validate", "--pretty"]),
+            Some(Command::Validate(&["--pretty"]))
+        );
+        assert_eq!(parse_args(&["explain"]), Some(Command::Explain(&[])));
+        assert_eq!(parse_args(&["seek"]), None);
+        assert_eq!(parse_args(&["dump", "a.bipf"]), None);
+        assert_eq!(parse_args(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            parse_path("value.content.0").unwrap(),
+            vec![
+                Segment::Key(String::from("value")),
+                Segment::Key(String::from("content")),
+                Segment::Index(0),
+            ]
+        );
+        assert_eq!(parse_path("").unwrap(), vec![]);
+        assert_eq!(
+            parse_path(r#"["a.b", 2, "--pretty"]"#).unwrap(),
+            vec![
+                Segment::Key(String::from("a.b")),
+                Segment::Index(2),
+                Segment::Key(String::from("--pretty")),
+            ]
+        );
+        assert!(parse_path("[1.5]").is_err());
+        assert!(parse_path("[true]").is_err());
+        assert!(parse_path("[\"a\"").is_err());
+    }
+
+    #[test]
+    fn test_seek_path() {
+        let buf = serde_json::json!({"a": [1, {"b": "x"}]}).to_bipf().unwrap();
+        let segments = parse_path("a.1.b").unwrap();
+        let segments: Vec<PathSegment> = segments.iter().map(PathSegment::from).collect();
+        let found = seek_segments(&buf, 0, &segments).unwrap().unwrap();
+
+        assert_eq!(decode_rec(&buf, found).unwrap(), serde_json::json!("x"));
+        assert!(seek(&buf, "a.5").is_err());
+    }
+}
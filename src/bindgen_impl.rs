@@ -1,4 +1,6 @@
 use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
 
 use node_bindgen::derive::node_bindgen;
 use node_bindgen::sys::*;
@@ -6,7 +8,6 @@ use node_bindgen::core::NjError;
 use node_bindgen::core::TryIntoJs;
 use node_bindgen::core::val::*;
 use node_bindgen::core::buffer::ArrayBuffer;
-use integer_encoding::*;
 
 #[node_bindgen(name="decode")]
 fn bindgen_decode(value: &[u8], start: f64, env: JsEnv) -> Result<napi_value, NjError> {
@@ -18,16 +19,13 @@ pub fn decode_rec_bindgen(
     buf: &[u8],
     start: usize,
 ) -> Result<napi_value, NjError> {
-    let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start..]);
-    let (tag, bytes) = match decoded {
-        Some(v) => Ok(v),
-        None => Err(NjError::Other(String::from(""))),
-    }?;
+    let (field_type, len, bytes) = read_tag(buf, start).map_err(nj_error)?;
 
-    let field_type = tag & TAG_MASK;
-    let len = tag >> TAG_SIZE;
+    decode_type_bindgen(env, field_type, buf, start + bytes, len, 0)
+}
 
-    decode_type_bindgen(env, field_type, buf, start + bytes, len)
+fn nj_error(e: BipfError) -> NjError {
+    NjError::Other(e.to_string())
 }
 
 pub fn decode_type_bindgen(
@@ -36,15 +34,17 @@ pub fn decode_type_bindgen(
     buf: &[u8],
     start: usize,
     len: usize,
+    depth: usize,
 ) -> Result<napi_value, NjError> {
     match field_type {
         STRING => decode_string_bindgen(cx, buf, start, len),
         BOOLNULL => decode_boolnull_bindgen(cx, buf, start, len),
-        INT => decode_integer_bindgen(cx, buf, start),
-        DOUBLE => decode_double_bindgen(cx, buf, start),
-        ARRAY => decode_array_bindgen(cx, buf, start, len),
-        OBJECT => decode_object_bindgen(cx, buf, start, len),
+        INT => decode_integer_bindgen(cx, buf, start, len),
+        DOUBLE => decode_double_bindgen(cx, buf, start, len),
+        ARRAY => decode_array_bindgen(cx, buf, start, len, depth),
+        OBJECT => decode_object_bindgen(cx, buf, start, len, depth),
         BUFFER => Ok(decode_buffer_bindgen(cx, buf, start, len)?),
+        EXTENSION => decode_extension_bindgen(cx, buf, start, len),
         _ => Err(NjError::Other(String::from(""))),
     }
 }
@@ -55,21 +55,11 @@ pub fn decode_boolnull_bindgen(
     start: usize,
     len: usize,
 ) -> Result<napi_value, NjError> {
-    if len == 0 {
-        cx.get_null()
-    } else {
-        let s = buf[start];
-        if s > 2 {
-            Err(NjError::Other(String::from("Invalid boolnull")))
-        } else {
-            if len > 1 {
-                Err(NjError::Other(String::from(
-                    "Invalid boolnull, len must be > 1",
-                )))
-            } else {
-                cx.create_boolean(if s == 1 { true } else { false })
-            }
-        }
+    match payload(buf, start, len).map_err(nj_error)? {
+        [] => cx.get_null(),
+        [UNDEFINED] => cx.get_undefined(),
+        [s] if *s <= 1 => cx.create_boolean(*s == 1),
+        _ => Err(nj_error(BipfError::InvalidBoolNull { offset: start })),
     }
 }
 
@@ -96,7 +86,7 @@ pub fn decode_string_bindgen(
     // };
     // Ok(js_value)
 
-    cx.create_string_utf8_from_bytes(&buf[start..start + len])
+    cx.create_string_utf8_from_bytes(payload(buf, start, len).map_err(nj_error)?)
 }
 
 pub fn decode_buffer_bindgen(
@@ -105,17 +95,42 @@ pub fn decode_buffer_bindgen(
     start: usize,
     len: usize,
 ) -> Result<napi_value, NjError>{
-    ArrayBuffer::new(buf[start..start + len].to_vec()).try_to_js(&cx)
+    ArrayBuffer::new(payload(buf, start, len).map_err(nj_error)?.to_vec()).try_to_js(&cx)
+}
+
+/// Like `decode_extension_neon`, `{type: 'Extension', subtype, data}` for every subtype.
+pub fn decode_extension_bindgen(
+    cx: JsEnv,
+    buf: &[u8],
+    start: usize,
+    len: usize,
+) -> Result<napi_value, NjError> {
+    let (subtype, data) = split_extension(buf, start, len).map_err(nj_error)?;
+    let obj = cx.create_object()?;
+    let fields = [
+        (c"type", cx.create_string_utf8("Extension")?),
+        (c"subtype", cx.create_double(subtype as f64)?),
+        (c"data", decode_buffer_bindgen(cx, data, 0, data.len())?),
+    ];
+
+    for (key, value) in fields {
+        unsafe {
+            napi_set_named_property(cx.inner(), obj, key.as_ptr(), value);
+        }
+    }
+    Ok(obj)
 }
 
 pub fn decode_integer_bindgen(
     cx: JsEnv,
     buf: &[u8],
     start: usize,
+    len: usize,
 ) -> Result<napi_value, NjError>{
-    let bytes: [u8; 4] = buf[start..start + 4]
-        .try_into()
-        .expect("slice with incorrect length");
+    let bytes: [u8; 4] = match payload(buf, start, len).map_err(nj_error)?.try_into() {
+        Ok(b) => b,
+        Err(_) => return Err(nj_error(BipfError::InvalidLength { offset: start })),
+    };
     cx.create_double(i32::from_le_bytes(bytes)as f64)
 }
 
@@ -123,10 +138,12 @@ pub fn decode_double_bindgen(
     cx: JsEnv,
     buf: &[u8],
     start: usize,
+    len: usize,
 ) -> Result<napi_value, NjError>{
-    let bytes: [u8; 8] = buf[start..start + 8]
-        .try_into()
-        .expect("slice with incorrect length");
+    let bytes: [u8; 8] = match payload(buf, start, len).map_err(nj_error)?.try_into() {
+        Ok(b) => b,
+        Err(_) => return Err(nj_error(BipfError::InvalidLength { offset: start })),
+    };
     cx.create_double(f64::from_le_bytes(bytes))
 }
 
@@ -135,25 +152,22 @@ pub fn decode_array_bindgen(
     buf: &[u8],
     start: usize,
     len: usize,
+    depth: usize,
 ) -> Result<napi_value, NjError>{
-    let mut c = 0;
+    if depth >= MAX_DEPTH {
+        return Err(nj_error(BipfError::DepthExceeded { offset: start }));
+    }
+
+    let end = start + len;
+    let mut c = start;
     let arr = cx.create_array_with_len(0)?;
     let mut i = 0;
-    while c < len {
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            Some(v) => Result::Ok(v),
-            None => Err(NjError::Other(String::from(
-                "Could not decode varint"
-            ))),
-        }?;
+    while c < end {
+        let (field_type, len, bytes) = read_tag_within(buf, c, end).map_err(nj_error)?;
 
         c += bytes;
 
-        let field_type = tag & TAG_MASK;
-        let len = tag >> TAG_SIZE;
-
-        cx.set_element(arr, decode_type_bindgen(cx, field_type, buf, start + c, len)?, i)?;
+        cx.set_element(arr, decode_type_bindgen(cx, field_type, buf, c, len, depth + 1)?, i)?;
         i += 1;
         c += len;
     }
@@ -166,21 +180,20 @@ pub fn decode_object_bindgen(
     buf: &[u8],
     start: usize,
     len: usize,
+    depth: usize,
 ) -> Result<napi_value, NjError>{
-    let mut c = 0;
+    if depth >= MAX_DEPTH {
+        return Err(nj_error(BipfError::DepthExceeded { offset: start }));
+    }
+
+    let end = start + len;
+    let mut c = start;
     let obj = cx.create_object()?;
 
-    while c < len {
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            Some(v) => Result::Ok(v),
-            None => Err(NjError::Other(String::from(
-                "Could not decode varint"
-            ))),
-        }?;
+    while c < end {
+        let (_, len, bytes) = read_tag_within(buf, c, end).map_err(nj_error)?;
         c += bytes;
-        let len = tag >> TAG_SIZE;
-        let key = match std::ffi::CString::new(&buf[start+c ..start +c+ len]) {
+        let key = match std::ffi::CString::new(&buf[c..c + len]) {
             Ok(s) => Ok(s),
             Err(_) => Err(NjError::Other(String::from(
                 "Could not create string"
@@ -188,19 +201,10 @@ pub fn decode_object_bindgen(
         }?;
         c += len;
 
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            Some(v) => Result::Ok(v),
-            None => Err(NjError::Other(String::from(
-                "Could not decode varint"
-            ))),
-        }?;
-
-        let field_type = tag & TAG_MASK;
-        let len = tag >> TAG_SIZE;
+        let (field_type, len, bytes) = read_tag_within(buf, c, end).map_err(nj_error)?;
 
         c += bytes;
-        let value = decode_type_bindgen(cx, field_type, buf, start + c, len)?;
+        let value = decode_type_bindgen(cx, field_type, buf, c, len, depth + 1)?;
         c += len;
         
         unsafe {
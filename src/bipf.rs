@@ -1,8 +1,11 @@
+use crate::error::BipfError;
+use crate::extension::{extension_to_json, split_extension};
+use crate::iter::{ArrayIter, ObjectIter};
+use crate::value::{bytes_to_json, BytesMapping, NonFiniteMapping};
 use either::*;
-use indexmap::IndexMap;
 use integer_encoding::VarInt;
 use serde_json::Value;
-use std::io::*;
+use std::io::Write;
 
 pub const STRING: usize = 0; // 000
 pub const BUFFER: usize = 1; // 001
@@ -11,7 +14,7 @@ pub const DOUBLE: usize = 3; // 011 //use next 8 bytes to encode 64bit float
 pub const ARRAY: usize = 4; // 100
 pub const OBJECT: usize = 5; // 101
 pub const BOOLNULL: usize = 6; // 110 //and use the rest of the byte as true/false/null
-pub const RESERVED: u64 = 7; // 111
+pub const EXTENSION: usize = 7; // 111 //subtype varint, then the extension's own payload
 
 pub const TAG_SIZE: usize = 3;
 pub const TAG_MASK: usize = 7;
@@ -19,420 +22,706 @@ pub const TAG_MASK: usize = 7;
 pub const JSON_INT_SIZE: usize = 4;
 pub const JSON_DOUBLE_SIZE: usize = 8;
 pub const JSON_BOOL_SIZE: usize = 1;
+/// The boolnull payload JS uses for `undefined`, next to 0 and 1 for false and true.
+pub const UNDEFINED: u8 = 2;
 pub const JSON_NULL_SIZE: usize = 0;
 
-pub const MAX_I32: i64 = 4294967296;
+/// Containers nested deeper than this are rejected instead of risking a stack overflow.
+pub const MAX_DEPTH: usize = 512;
+
+type Result<T> = std::result::Result<T, BipfError>;
 
 pub trait Bipf {
     fn to_bipf(&self) -> Result<Vec<u8>>;
+
+    /// Encodes into `buf` at `start` and returns the number of bytes written.
+    ///
+    /// The default implementation encodes into a temporary `Vec` and copies it over.
+    fn encode_into(&self, buf: &mut [u8], start: usize) -> Result<usize> {
+        let encoded = self.to_bipf()?;
+        check_space(buf, start, encoded.len())?;
+        buf[start..start + encoded.len()].copy_from_slice(&encoded);
+        Ok(encoded.len())
+    }
 }
 
 impl Bipf for Value {
     fn to_bipf(&self) -> Result<Vec<u8>> {
-        JType::new(self).encode()
-    }
-}
-
-enum JType<'a> {
-    String {
-        v: &'a String,
-        l: usize,
-    },
-    Int {
-        v: i32,
-    },
-    Double {
-        v: Either<i64, f64>,
-    },
-    Array {
-        v: Vec<JType<'a>>,
-        l: usize,
-    },
-    Object {
-        v: IndexMap<&'a String, JType<'a>>,
-        l: usize,
-    },
-    BoolNull {
-        v: Option<bool>,
-        l: usize,
-    },
-}
-
-impl<'a> JType<'a> {
-    pub fn new(input: &'a Value) -> JType {
-        match input {
-            Value::Null => JType::BoolNull {
-                v: None,
-                l: JSON_NULL_SIZE,
-            },
-            Value::Bool(v) => JType::BoolNull {
-                v: Some(*v),
-                l: JSON_BOOL_SIZE,
-            },
-            Value::String(s) => JType::String { l: s.len(), v: s },
-            Value::Array(arr) => {
-                let v: Vec<JType> = arr.iter().map(|x| JType::new(x)).collect();
-                let mut l = 0;
-                for x in &v {
-                    let base_len = JType::length(&x);
-                    l += base_len + (base_len << TAG_SIZE).required_space()
-                }
-                JType::Array { v, l }
+        encode_with(self, &EncodeOptions::default())
+    }
+
+    fn encode_into(&self, buf: &mut [u8], start: usize) -> Result<usize> {
+        let (lengths, needed) = measure(self);
+        check_space(buf, start, needed)?;
+        write_value(
+            self,
+            &EncodeOptions::default(),
+            &mut lengths.into_iter(),
+            &mut &mut buf[start..start + needed],
+        )?;
+        Ok(needed)
+    }
+}
+
+/// How integers outside of the i32 range, which are encoded as doubles, are treated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntegerPolicy {
+    /// Round to the nearest double, like JS numbers. Beyond 2^53 this may lose precision.
+    Double,
+    /// Fail with `InexactInteger` rather than lose precision.
+    Exact,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodeOptions {
+    pub integers: IntegerPolicy,
+    /// NaN and the infinities are written as doubles when preserved, like Node does.
+    ///
+    /// This applies to `BipfValue` and serde, serde_json numbers are always finite.
+    pub non_finite: NonFiniteMapping,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            integers: IntegerPolicy::Double,
+            non_finite: NonFiniteMapping::Preserve,
+        }
+    }
+}
+
+/// Like `to_bipf`, with control over how numbers are encoded.
+pub fn encode_with(value: &Value, options: &EncodeOptions) -> Result<Vec<u8>> {
+    let (lengths, needed) = measure(value);
+    let mut buf = Vec::with_capacity(needed);
+    write_value(value, options, &mut lengths.into_iter(), &mut buf)?;
+    Ok(buf)
+}
+
+/// The number policy shared by every encoder: integers in the i32 range are INT,
+/// everything else is a DOUBLE.
+pub fn encode_integer(v: i128, policy: IntegerPolicy) -> Result<Either<i32, f64>> {
+    if let Ok(i) = i32::try_from(v) {
+        return Ok(Left(i));
+    }
+
+    let d = v as f64;
+    if policy == IntegerPolicy::Exact && d as i128 != v {
+        Err(BipfError::InexactInteger(v))
+    } else {
+        Ok(Right(d))
+    }
+}
+
+/// Returns the i32 a double holds, if it holds an integer in the i32 range.
+pub fn double_as_int(v: f64) -> Option<i32> {
+    if v.fract() == 0.0 && v >= i32::MIN as f64 && v <= i32::MAX as f64 {
+        Some(v as i32)
+    } else {
+        None
+    }
+}
+
+/// Encodes `value` into `buf` at `start`, like `encode(value, buffer, start)` in the JS library.
+/// Returns the number of bytes written.
+pub fn encode_into<T: Bipf + ?Sized>(value: &T, buf: &mut [u8], start: usize) -> Result<usize> {
+    value.encode_into(buf, start)
+}
+
+pub(crate) fn check_space(buf: &[u8], start: usize, needed: usize) -> Result<()> {
+    let available = buf.len().saturating_sub(start);
+    if needed > available {
+        Err(BipfError::BufferTooSmall { needed, available })
+    } else {
+        Ok(())
+    }
+}
+
+fn number(n: &serde_json::Number, policy: IntegerPolicy) -> Result<Either<i32, f64>> {
+    if let Some(i) = n.as_i64() {
+        encode_integer(i as i128, policy)
+    } else if let Some(u) = n.as_u64() {
+        encode_integer(u as i128, policy)
+    } else {
+        Ok(Right(n.as_f64().unwrap_or(f64::NAN)))
+    }
+}
+
+/// Size of the payload of `value`, tag excluded.
+fn payload_length(value: &Value) -> usize {
+    match value {
+        Value::Null => JSON_NULL_SIZE,
+        Value::Bool(_) => JSON_BOOL_SIZE,
+        // The policy only decides whether to fail, not the type
+        Value::Number(n) => match number(n, IntegerPolicy::Double) {
+            Ok(Left(_)) => JSON_INT_SIZE,
+            _ => JSON_DOUBLE_SIZE,
+        },
+        Value::String(s) => s.len(),
+        Value::Array(arr) => arr.iter().map(encoding_length).sum(),
+        Value::Object(o) => o
+            .iter()
+            .map(|(k, v)| k.len() + (k.len() << TAG_SIZE).required_space() + encoding_length(v))
+            .sum(),
+    }
+}
+
+/// Exact size of `value` once encoded, tag included, computed without encoding it.
+pub fn encoding_length(value: &Value) -> usize {
+    tagged_length(payload_length(value))
+}
+
+/// Size of a value with a payload of `len` bytes, tag included.
+pub(crate) fn tagged_length(len: usize) -> usize {
+    len + (len << TAG_SIZE).required_space()
+}
+
+/// Payload lengths of the containers in `value`, in the order `write_value` meets them, and
+/// the size of the whole encoded value.
+fn measure(value: &Value) -> (Vec<usize>, usize) {
+    let mut lengths = Vec::new();
+    let len = container_lengths(value, &mut lengths);
+    (lengths, tagged_length(len))
+}
+
+/// Payload length of `value`, pushing those of the containers in it to `lengths` on the way.
+fn container_lengths(value: &Value, lengths: &mut Vec<usize>) -> usize {
+    let i = lengths.len();
+    let len = match value {
+        Value::Array(arr) => {
+            lengths.push(0);
+            arr.iter()
+                .map(|x| tagged_length(container_lengths(x, lengths)))
+                .sum()
+        }
+        Value::Object(o) => {
+            lengths.push(0);
+            o.iter()
+                .map(|(k, v)| tagged_length(k.len()) + tagged_length(container_lengths(v, lengths)))
+                .sum()
+        }
+        _ => return payload_length(value),
+    };
+    lengths[i] = len;
+    len
+}
+
+/// Writes `value` in one pass, taking the length of each container from those `measure`
+/// found.
+fn write_value<W: Write>(
+    value: &Value,
+    options: &EncodeOptions,
+    lengths: &mut std::vec::IntoIter<usize>,
+    out: &mut W,
+) -> Result<()> {
+    match value {
+        Value::Null => write_tag_to(out, BOOLNULL, JSON_NULL_SIZE)?,
+        Value::Bool(b) => {
+            write_tag_to(out, BOOLNULL, JSON_BOOL_SIZE)?;
+            out.write_all(&[*b as u8])?
+        }
+        Value::Number(n) => match number(n, options.integers)? {
+            Left(i) => {
+                write_tag_to(out, INT, JSON_INT_SIZE)?;
+                out.write_all(&i.to_le_bytes())?
             }
-            Value::Number(n) => {
-                if n.is_i64() {
-                    let i64 = n.as_i64().unwrap();
-                    if i64.abs() < MAX_I32 {
-                        JType::Int { v: i64 as i32 }
-                    } else {
-                        JType::Double { v: Left(i64) }
-                    }
-                } else {
-                    JType::Double {
-                        v: Right(n.as_f64().unwrap()),
-                    }
-                }
+            Right(d) => {
+                write_tag_to(out, DOUBLE, JSON_DOUBLE_SIZE)?;
+                out.write_all(&d.to_le_bytes())?
             }
-            Value::Object(o) => {
-                let v: IndexMap<&'a String, JType> =
-                    o.iter().map(|(k, v)| (k, JType::new(v))).collect();
-                let mut l = 0;
-                for (k, v) in &v {
-                    let key_len = k.len();
-                    let val_length = JType::length(&v);
-                    l += key_len
-                        + (key_len << TAG_SIZE).required_space()
-                        + val_length
-                        + (val_length << TAG_SIZE).required_space()
-                }
-                JType::Object { v, l }
+        },
+        Value::String(s) => {
+            write_tag_to(out, STRING, s.len())?;
+            out.write_all(s.as_bytes())?
+        }
+        Value::Array(arr) => {
+            write_tag_to(out, ARRAY, next_length(lengths))?;
+            for x in arr {
+                write_value(x, options, lengths, out)?;
+            }
+        }
+        Value::Object(o) => {
+            write_tag_to(out, OBJECT, next_length(lengths))?;
+            for (k, v) in o {
+                write_tag_to(out, STRING, k.len())?;
+                out.write_all(k.as_bytes())?;
+                write_value(v, options, lengths, out)?;
             }
         }
     }
+    Ok(())
+}
 
-    pub fn encode_rec(&self, buf: &mut Vec<u8>, start: usize) -> Result<usize> {
-        let length_varint = self.length() << TAG_SIZE | self.get_type();
-        let varint = length_varint.encode_var_vec();
-        let varint_length = varint.len();
-        buf.write(&varint)?;
-
-        Ok(varint_length
-            + (match self {
-                JType::String { v, l: _ } => buf.write(v.as_bytes()),
-                JType::Int { v } => buf.write(&v.to_le_bytes()),
-                JType::Double { v: Left(int) } => buf.write(&int.to_le_bytes()),
-                JType::Double { v: Right(float) } => buf.write(&float.to_le_bytes()),
-                JType::BoolNull { v, l: _ } => match v {
-                    None => Ok(JSON_NULL_SIZE),
-                    Some(b) => buf.write(&[if *b { 1 } else { 0 }]),
-                },
-                JType::Array { v, l: _ } => {
-                    let mut p = start;
-                    for i in v {
-                        p += i.encode_rec(buf, p)?
-                    }
-                    Ok(p - start)
-                }
-                JType::Object { v, l: _ } => {
-                    let mut p = start;
-                    for (k, u) in v {
-                        p += JType::String { v: k, l: k.len() }.encode_rec(buf, p)?;
-                        p += u.encode_rec(buf, p)?;
-                    }
-                    Ok(p - start)
-                }
-            })?)
-    }
+/// The length of the next container, measured beforehand in the same order.
+pub(crate) fn next_length(lengths: &mut std::vec::IntoIter<usize>) -> usize {
+    lengths
+        .next()
+        .expect("containers are measured before they are written")
+}
 
-    pub fn encode(&self) -> Result<Vec<u8>> {
-        let mut buf: Vec<u8> = Vec::with_capacity(self.length());
-        self.encode_rec(&mut buf, 0)?;
+pub(crate) fn write_tag_to<W: Write>(
+    out: &mut W,
+    field_type: usize,
+    len: usize,
+) -> std::io::Result<()> {
+    let mut varint = [0u8; 10];
+    let bytes = (len << TAG_SIZE | field_type).encode_var(&mut varint);
+    out.write_all(&varint[..bytes])
+}
+
+/// Writes the tag varint for a value of `field_type` whose payload is `len` bytes long.
+pub(crate) fn write_tag(buf: &mut Vec<u8>, field_type: usize, len: usize) {
+    let mut varint = [0u8; 10];
+    let bytes = (len << TAG_SIZE | field_type).encode_var(&mut varint);
+    buf.extend_from_slice(&varint[..bytes]);
+}
 
-        buf.flush()?;
+/// Opens a container whose length is not known yet, reserving one byte for its tag.
+/// Returns the offset of the payload, to be handed to `end_container`.
+pub(crate) fn begin_container(buf: &mut Vec<u8>) -> usize {
+    buf.push(0);
+    buf.len()
+}
 
-        Ok(buf)
+/// Closes a container opened with `begin_container`, backpatching its tag and
+/// shifting the payload when the tag needs more than the reserved byte.
+pub(crate) fn end_container(buf: &mut Vec<u8>, start: usize, field_type: usize) {
+    let mut varint = [0u8; 10];
+    let bytes = ((buf.len() - start) << TAG_SIZE | field_type).encode_var(&mut varint);
+    if bytes == 1 {
+        buf[start - 1] = varint[0];
+    } else {
+        buf.splice(start - 1..start, varint[..bytes].iter().copied());
     }
+}
 
-    pub fn length(&self) -> usize {
-        match self {
-            JType::String { v: _, l } => *l,
-            JType::Int { v: _ } => JSON_INT_SIZE,
-            JType::Double { v: _ } => JSON_DOUBLE_SIZE,
-            JType::Array { v: _, l } => *l,
-            JType::Object { v: _, l } => *l,
-            JType::BoolNull { v: _, l } => *l,
-        }
+/// Reads the tag varint at `start` and returns `(field_type, len, tag_bytes)`,
+/// failing if the varint is malformed or the declared payload does not fit in `buf`.
+pub fn read_tag(buf: &[u8], start: usize) -> Result<(usize, usize, usize)> {
+    let decoded: Option<(usize, usize)> = buf.get(start..).and_then(VarInt::decode_var);
+    let (tag, bytes) = match decoded {
+        Some(v) => v,
+        None => return Err(BipfError::Truncated { offset: start }),
+    };
+    let len = tag >> TAG_SIZE;
+
+    if len > buf.len() - start - bytes {
+        Err(BipfError::Truncated { offset: start })
+    } else {
+        Ok((tag & TAG_MASK, len, bytes))
     }
+}
 
-    pub fn get_type(&self) -> usize {
-        match self {
-            JType::String { v: _, l: _ } => STRING,
-            JType::Int { v: _ } => INT,
-            JType::Double { v: _ } => DOUBLE,
-            JType::Array { v: _, l: _ } => ARRAY,
-            JType::Object { v: _, l: _ } => OBJECT,
-            JType::BoolNull { v: _, l: _ } => BOOLNULL,
-        }
+/// Like `read_tag`, but the value must also end before `end`, the end of its parent container.
+pub fn read_tag_within(buf: &[u8], start: usize, end: usize) -> Result<(usize, usize, usize)> {
+    let (field_type, len, bytes) = read_tag(buf, start)?;
+
+    if start + bytes + len > end {
+        Err(BipfError::LengthOverflow { offset: start })
+    } else {
+        Ok((field_type, len, bytes))
     }
 }
 
-pub fn decode(buf: &Vec<u8>) -> Result<Value> {
+/// Returns `buf[start..start + len]`, or an error if the buffer is too short.
+pub(crate) fn payload(buf: &[u8], start: usize, len: usize) -> Result<&[u8]> {
+    start
+        .checked_add(len)
+        .and_then(|end| buf.get(start..end))
+        .ok_or(BipfError::Truncated { offset: start })
+}
+
+pub fn decode(buf: &[u8]) -> Result<Value> {
     decode_rec(buf, 0)
 }
 
-pub fn decode_rec(buf: &Vec<u8>, start: usize) -> Result<Value> {
-    let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start..]);
-    let (tag, bytes) = match decoded {
-        None => Err(Error::from(ErrorKind::InvalidInput)),
-        Some(v) => Result::Ok(v),
-    }?;
+pub fn decode_rec(buf: &[u8], start: usize) -> Result<Value> {
+    decode_at_depth(buf, start, buf.len(), 0)
+}
 
-    let field_type = tag & TAG_MASK;
-    let len = tag >> TAG_SIZE;
+fn decode_at_depth(buf: &[u8], start: usize, end: usize, depth: usize) -> Result<Value> {
+    // The tag has three bits, every type it can hold is a known one
+    let (field_type, len, bytes) = read_tag_within(buf, start, end)?;
+    decode_type_at_depth(field_type, buf, start + bytes, len, depth)
+}
 
-    decode_type(field_type, buf, start + bytes, len)
+pub fn decode_type(field_type: usize, buf: &[u8], start: usize, len: usize) -> Result<Value> {
+    decode_type_at_depth(field_type, buf, start, len, 0)
 }
 
-pub fn decode_type(field_type: usize, buf: &Vec<u8>, start: usize, len: usize) -> Result<Value> {
+fn decode_type_at_depth(
+    field_type: usize,
+    buf: &[u8],
+    start: usize,
+    len: usize,
+    depth: usize,
+) -> Result<Value> {
     match field_type {
         STRING => decode_string(buf, start, len),
+        BUFFER => decode_buffer(buf, start, len),
         BOOLNULL => decode_boolnull(buf, start, len),
-        INT => decode_integer(buf, start),
-        DOUBLE => decode_double(buf, start),
-        ARRAY => decode_array(buf, start, len),
-        OBJECT => decode_object(buf, start, len),
-        _ => Err(Error::new(ErrorKind::Other, "invalid type")),
+        INT if len == JSON_INT_SIZE => decode_integer(buf, start),
+        DOUBLE if len == JSON_DOUBLE_SIZE => decode_double(buf, start),
+        INT | DOUBLE => Err(BipfError::InvalidLength { offset: start }),
+        ARRAY => decode_array_at_depth(buf, start, len, depth),
+        OBJECT => decode_object_at_depth(buf, start, len, depth),
+        EXTENSION => {
+            let (subtype, data) = split_extension(buf, start, len)?;
+            Ok(extension_to_json(subtype, data))
+        }
+        _ => Err(BipfError::InvalidTag {
+            offset: start,
+            tag: field_type,
+        }),
     }
 }
 
-pub fn decode_boolnull(buf: &Vec<u8>, start: usize, len: usize) -> Result<Value> {
-    if len == 0 {
-        Ok(Value::Null)
-    } else {
-        if buf[start] > 2 {
-            Err(Error::new(ErrorKind::Other, "Invalid boolnull"))
-        } else {
-            if len > 1 {
-                Err(Error::new(
-                    ErrorKind::Other,
-                    "Invalid boolnull, len must be > 1",
-                ))
-            } else {
-                Ok(Value::Bool(if buf[start] == 1 { true } else { false }))
-            }
-        }
+/// `undefined` becomes null, which is what `JSON.stringify` does to it in arrays.
+pub fn decode_boolnull(buf: &[u8], start: usize, len: usize) -> Result<Value> {
+    match payload(buf, start, len)? {
+        [] | [UNDEFINED] => Ok(Value::Null),
+        [b] if *b <= 1 => Ok(Value::Bool(*b == 1)),
+        _ => Err(BipfError::InvalidBoolNull { offset: start }),
     }
 }
 
-pub fn decode_string(buf: &Vec<u8>, start: usize, len: usize) -> Result<Value> {
-    let raw_str = std::str::from_utf8(&buf[start..start + len]);
-    match raw_str {
-        std::result::Result::Ok(v) => Ok(Value::String(String::from(v))),
-        std::result::Result::Err(_) => Err(Error::new(
-            ErrorKind::Other,
-            "Could not decode utf-8 string",
-        )),
+pub fn decode_string(buf: &[u8], start: usize, len: usize) -> Result<Value> {
+    match std::str::from_utf8(payload(buf, start, len)?) {
+        Ok(v) => Ok(Value::String(String::from(v))),
+        Err(e) => Err(BipfError::InvalidUtf8 {
+            offset: start + e.valid_up_to(),
+        }),
     }
 }
 
-pub fn decode_integer(buf: &Vec<u8>, start: usize) -> Result<Value> {
-    let bytes: [u8; 4] = buf[start..start + 4]
-        .try_into()
-        .expect("slice with incorrect length");
-    Ok(serde_json::to_value(i32::from_le_bytes(bytes))?)
+/// Buffers have no JSON counterpart, they are decoded the way `JSON.stringify` renders a Node Buffer.
+pub fn decode_buffer(buf: &[u8], start: usize, len: usize) -> Result<Value> {
+    bytes_to_json(payload(buf, start, len)?, BytesMapping::NodeBuffer)
 }
 
-pub fn decode_double(buf: &Vec<u8>, start: usize) -> Result<Value> {
-    let bytes: [u8; 8] = buf[start..start + 8]
-        .try_into()
-        .expect("slice with incorrect length");
-    Ok(serde_json::to_value(f64::from_le_bytes(bytes))?)
+pub fn decode_integer(buf: &[u8], start: usize) -> Result<Value> {
+    let mut bytes = [0u8; JSON_INT_SIZE];
+    bytes.copy_from_slice(payload(buf, start, JSON_INT_SIZE)?);
+    Ok(Value::from(i32::from_le_bytes(bytes)))
 }
 
-pub fn decode_array(buf: &Vec<u8>, start: usize, len: usize) -> Result<Value> {
-    let mut c = 0;
-    let mut vec: Vec<Value> = Vec::new();
-
-    while c < len {
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            None => Result::Err(Error::from(ErrorKind::InvalidInput)),
-            Some(v) => Result::Ok(v),
-        }?;
+/// NaN and the infinities become null, see `decode_with` for other mappings.
+pub fn decode_double(buf: &[u8], start: usize) -> Result<Value> {
+    let mut bytes = [0u8; JSON_DOUBLE_SIZE];
+    bytes.copy_from_slice(payload(buf, start, JSON_DOUBLE_SIZE)?);
+    Ok(Value::from(f64::from_le_bytes(bytes)))
+}
 
-        c += bytes;
+pub fn decode_array(buf: &[u8], start: usize, len: usize) -> Result<Value> {
+    decode_array_at_depth(buf, start, len, 0)
+}
 
-        let field_type = tag & TAG_MASK;
-        let len = tag >> TAG_SIZE;
+fn decode_array_at_depth(buf: &[u8], start: usize, len: usize, depth: usize) -> Result<Value> {
+    if depth >= MAX_DEPTH {
+        return Err(BipfError::DepthExceeded { offset: start });
+    }
 
-        vec.push(decode_type(field_type, buf, start + c, len)?);
+    let end = start + payload(buf, start, len)?.len();
+    let mut c = start;
+    let mut vec: Vec<Value> = Vec::new();
 
-        c += len;
+    while c < end {
+        let (_, len, bytes) = read_tag_within(buf, c, end)?;
+        vec.push(decode_at_depth(buf, c, end, depth + 1)?);
+        c += bytes + len;
     }
 
     Ok(Value::Array(vec))
 }
 
-pub fn decode_object(buf: &Vec<u8>, start: usize, len: usize) -> Result<Value> {
-    let mut c = 0;
+pub fn decode_object(buf: &[u8], start: usize, len: usize) -> Result<Value> {
+    decode_object_at_depth(buf, start, len, 0)
+}
+
+fn decode_object_at_depth(buf: &[u8], start: usize, len: usize, depth: usize) -> Result<Value> {
+    if depth >= MAX_DEPTH {
+        return Err(BipfError::DepthExceeded { offset: start });
+    }
+
+    let end = start + payload(buf, start, len)?.len();
+    let mut c = start;
     let mut map: serde_json::Map<String, Value> = serde_json::Map::new();
 
-    while c < len {
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            None => Result::Err(Error::from(ErrorKind::InvalidInput)),
-            Some(v) => Result::Ok(v),
-        }?;
-        c += bytes;
-        let len = tag >> TAG_SIZE;
-        let key: String = match decode_string(buf, start + c, len)? {
-            Value::String(key) => Ok(key),
-            _ => Err(Error::from(ErrorKind::InvalidInput)),
-        }?;
-        c += len;
-
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            None => Err(Error::from(ErrorKind::InvalidInput)),
-            Some(v) => Result::Ok(v),
-        }?;
-
-        let field_type = tag & TAG_MASK;
-        let len = tag >> TAG_SIZE;
-
-        c += bytes;
-        let value = decode_type(field_type, buf, start + c, len)?;
-        c += len;
+    while c < end {
+        let (_, len, bytes) = read_tag_within(buf, c, end)?;
+        let key: String = match decode_string(buf, c + bytes, len)? {
+            Value::String(key) => key,
+            _ => unreachable!(),
+        };
+        c += bytes + len;
+
+        let (_, len, bytes) = read_tag_within(buf, c, end)?;
+        let value = decode_at_depth(buf, c, end, depth + 1)?;
+        c += bytes + len;
         map.insert(key, value);
     }
 
     Ok(Value::Object(map))
 }
 
-pub fn seek_key(bytes: &Vec<u8>, start: Option<usize>, target: String) -> Option<usize> {
+/// Returns the offset of the value stored under `target` in the object at `start`,
+/// `None` if `start` is `None`, not an object, or lacks the key.
+pub fn seek_key(bytes: &[u8], start: Option<usize>, target: String) -> Result<Option<usize>> {
     match start {
-        None => None,
-        Some(start) => {
-            let decoded: (usize, usize) = VarInt::decode_var(&bytes[start..])?;
-            let tag = decoded.0;
-            let ty = tag & TAG_MASK;
-
-            if ty != OBJECT {
-                None
-            } else {
-                let mut c = decoded.1;
-                let len = tag >> TAG_SIZE;
-                let target_length = target.len();
-                let target_buf = target.as_bytes();
-                while c < len {
-                    let key_tag: (usize, usize) = VarInt::decode_var(&bytes[start + c..])?;
-                    c += key_tag.1;
-                    let key_len = key_tag.0 >> TAG_SIZE;
-                    let key_type = key_tag.0 & TAG_MASK;
-
-                    if key_type == STRING && target_length == key_len {
-                        if target_buf.eq(&bytes[start + c..start + c + target_length]) {
-                            let next_start = start + c + key_len;
-                            return Some(next_start);
-                        }
-                    }
-
-                    c += key_len;
-                    let value_tag: (usize, usize) = VarInt::decode_var(&bytes[start + c..])?;
-                    c += value_tag.1;
-                    let value_len = value_tag.0 >> TAG_SIZE;
-                    c += value_len;
+        None => Ok(None),
+        Some(start) => seek_key_bytes(bytes, start, target.as_bytes()),
+    }
+}
+
+/// `seek_key` over a raw key, for callers that already hold its bytes.
+pub fn seek_key_bytes(buf: &[u8], start: usize, target: &[u8]) -> Result<Option<usize>> {
+    let (field_type, len, bytes) = read_tag(buf, start)?;
+    if field_type != OBJECT {
+        return Ok(None);
+    }
+
+    let end = start + bytes + len;
+    let mut c = start + bytes;
+    while c < end {
+        let (key_type, key_len, key_bytes) = read_tag_within(buf, c, end)?;
+        let key_start = c + key_bytes;
+        c = key_start + key_len;
+
+        if key_type == STRING && &buf[key_start..c] == target {
+            return Ok(Some(c));
+        }
+
+        let (_, value_len, value_bytes) = read_tag_within(buf, c, end)?;
+        c += value_bytes + value_len;
+    }
+
+    Ok(None)
+}
+
+/// Returns the offset of the `index`th element of the array at `start`,
+/// `None` if it is not an array or is too short.
+pub fn seek_index(buf: &[u8], start: usize, index: usize) -> Result<Option<usize>> {
+    let (field_type, len, bytes) = read_tag(buf, start)?;
+    if field_type != ARRAY {
+        return Ok(None);
+    }
+
+    let end = start + bytes + len;
+    let mut c = start + bytes;
+    let mut i = 0;
+    while c < end {
+        if i == index {
+            return Ok(Some(c));
+        }
+        let (_, value_len, value_bytes) = read_tag_within(buf, c, end)?;
+        c += value_bytes + value_len;
+        i += 1;
+    }
+
+    Ok(None)
+}
+
+/// One step of a path: a key into an object or an index into an array.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathSegment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+impl<'a> From<&'a str> for PathSegment<'a> {
+    fn from(key: &'a str) -> Self {
+        PathSegment::Key(key)
+    }
+}
+
+impl<'a> From<usize> for PathSegment<'a> {
+    fn from(index: usize) -> Self {
+        PathSegment::Index(index)
+    }
+}
+
+/// Follows `path` from the value at `start` and returns the offset of the value it leads to.
+pub fn seek_segments(buf: &[u8], start: usize, path: &[PathSegment]) -> Result<Option<usize>> {
+    let mut c = start;
+    for segment in path {
+        let found = match segment {
+            PathSegment::Key(key) => seek_key_bytes(buf, c, key.as_bytes())?,
+            PathSegment::Index(index) => seek_index(buf, c, *index)?,
+        };
+        c = match found {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+    }
+
+    Ok(Some(c))
+}
+
+/// A path compiled once and run against many buffers.
+///
+/// Keys are stored already encoded, tag included, so matching a key is a single slice comparison.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeekPath {
+    steps: Vec<SeekStep>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum SeekStep {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+impl SeekPath {
+    pub fn compile(path: &[PathSegment]) -> SeekPath {
+        let steps = path
+            .iter()
+            .map(|segment| match segment {
+                PathSegment::Key(key) => {
+                    let mut encoded = Vec::with_capacity(key.len() + 2);
+                    write_tag(&mut encoded, STRING, key.len());
+                    encoded.extend_from_slice(key.as_bytes());
+                    SeekStep::Key(encoded)
                 }
+                PathSegment::Index(index) => SeekStep::Index(*index),
+            })
+            .collect();
+
+        SeekPath { steps }
+    }
 
-                None
+    /// Follows the path from the value at `start`, like `seek_segments`.
+    pub fn seek(&self, buf: &[u8], start: usize) -> Result<Option<usize>> {
+        let mut c = start;
+        for step in &self.steps {
+            let found = match step {
+                SeekStep::Key(encoded) => seek_encoded_key(buf, c, encoded)?,
+                SeekStep::Index(index) => seek_index(buf, c, *index)?,
+            };
+            c = match found {
+                Some(c) => c,
+                None => return Ok(None),
+            };
+        }
+
+        Ok(Some(c))
+    }
+
+    /// The path as an encoded array of strings and integers, the form taken by `seek_path`.
+    ///
+    /// Indexes past `i32::MAX` are encoded as -1, which like them never matches anything.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut steps = Vec::new();
+        for step in &self.steps {
+            match step {
+                SeekStep::Key(encoded) => steps.extend_from_slice(encoded),
+                SeekStep::Index(index) => {
+                    write_tag(&mut steps, INT, 4);
+                    let index = i32::try_from(*index).unwrap_or(-1);
+                    steps.extend_from_slice(&index.to_le_bytes());
+                }
             }
         }
+
+        let mut buf = Vec::with_capacity(steps.len() + 2);
+        write_tag(&mut buf, ARRAY, steps.len());
+        buf.extend_from_slice(&steps);
+        buf
     }
 }
 
-pub fn seek_path<'a>(
-    buf: &Vec<u8>,
-    start: usize,
-    target: &Vec<u8>,
-    t_start: usize,
-) -> Result<Option<usize>> {
-    match decode_rec(&target, t_start)? {
-        Value::Array(ary) => {
-            let mut start = Some(start);
-            for i in 0..ary.len() {
-                let b = &ary[i];
-                start = match b {
-                    // The clone here is due to serde_json's inner map not supporting lifetimes... yet
-                    Value::String(str) => Ok(seek_key(buf, start, str.clone())),
-                    _ => Err(Error::new(
-                        ErrorKind::Other,
-                        "path must be array of strings",
-                    )),
-                }?;
-            }
+/// `seek_key_bytes` with a key that is already encoded, tag included.
+fn seek_encoded_key(buf: &[u8], start: usize, encoded: &[u8]) -> Result<Option<usize>> {
+    let (field_type, len, bytes) = read_tag(buf, start)?;
+    if field_type != OBJECT {
+        return Ok(None);
+    }
 
-            Ok(None)
+    let end = start + bytes + len;
+    let mut c = start + bytes;
+    while c < end {
+        let (_, key_len, key_bytes) = read_tag_within(buf, c, end)?;
+        let key_end = c + key_bytes + key_len;
+        if &buf[c..key_end] == encoded {
+            return Ok(Some(key_end));
         }
-        _ => Err(Error::new(ErrorKind::Other, "path must be encoded array")),
+
+        let (_, value_len, value_bytes) = read_tag_within(buf, key_end, end)?;
+        c = key_end + value_bytes + value_len;
     }
+
+    Ok(None)
 }
 
-pub fn iterate(
-    buf: &Vec<u8>,
-    start: usize,
-    iter: fn(&Vec<u8>, usize, usize) -> bool,
-) -> Result<Option<usize>> {
-    let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start..]);
-    let (tag, bytes) = match decoded {
-        None => Err(Error::from(ErrorKind::InvalidInput)),
-        Some(v) => Result::Ok(v),
-    }?;
+/// Like `seek_segments`, with the path given as an encoded array of strings and integers
+/// starting at `t_start` in `target`.
+pub fn seek_path(buf: &[u8], start: usize, target: &[u8], t_start: usize) -> Result<Option<usize>> {
+    let (field_type, len, bytes) = read_tag(target, t_start)?;
+    if field_type != ARRAY {
+        return Err(BipfError::Message(String::from(
+            "path must be encoded array",
+        )));
+    }
 
-    let field_type = tag & TAG_MASK;
-    let len = tag >> TAG_SIZE;
+    let end = t_start + bytes + len;
+    let mut t = t_start + bytes;
+    let mut c = start;
+    while t < end {
+        let (segment_type, segment_len, segment_bytes) = read_tag_within(target, t, end)?;
+        let segment = &target[t + segment_bytes..t + segment_bytes + segment_len];
+        let found = match segment_type {
+            STRING => seek_key_bytes(buf, c, segment)?,
+            INT => match segment.try_into().map(i32::from_le_bytes) {
+                Ok(index) if index >= 0 => seek_index(buf, c, index as usize)?,
+                Ok(_) => None,
+                Err(_) => return Err(BipfError::InvalidLength { offset: t }),
+            },
+            _ => {
+                return Err(BipfError::InvalidTag {
+                    offset: t,
+                    tag: segment_type,
+                })
+            }
+        };
+        c = match found {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        t += segment_bytes + segment_len;
+    }
+
+    Ok(Some(c))
+}
+
+/// Calls `iter(buf, value_offset, key_offset)` for each entry of the object at `start`, or
+/// `iter(buf, value_offset, index)` for each element of an array, until it returns `true`.
+///
+/// Returns the offset of the value `iter` stopped at, `None` if it never did or if the value
+/// is not a container.
+pub fn iterate<F>(buf: &[u8], start: usize, mut iter: F) -> Result<Option<usize>>
+where
+    F: FnMut(&[u8], usize, usize) -> bool,
+{
+    let (field_type, len, bytes) = read_tag(buf, start)?;
 
     if field_type == OBJECT {
-        let mut c = bytes;
-        while c < len {
-            let key_start = start + c;
-            let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[key_start..]);
-            let (key_tag, bytes) = match decoded {
-                None => Err(Error::from(ErrorKind::InvalidInput)),
-                Some(v) => Result::Ok(v),
-            }?;
-
-            c += bytes + key_tag >> TAG_SIZE;
-
-            let value_start = start + c;
-            let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[value_start..]);
-            let (value_tag, bytes) = match decoded {
-                None => Err(Error::from(ErrorKind::InvalidInput)),
-                Some(v) => Result::Ok(v),
-            }?;
-
-            let next_start = bytes + (value_tag >> TAG_SIZE);
+        for entry in ObjectIter::new(buf, start)? {
+            let (key_start, value_start) = entry?;
             if iter(buf, value_start, key_start) {
-                return Ok(Some(start));
+                return Ok(Some(value_start));
             }
-            c += next_start;
         }
+        Ok(None)
     } else if field_type == ARRAY {
-        let mut i = 0;
-        let mut c = bytes;
-        while c < len {
-            if iter(buf, start + c, i) {
-                return Ok(Some(start));
+        for (i, value_start) in ArrayIter::new(buf, start)?.enumerate() {
+            let value_start = value_start?;
+            if iter(buf, value_start, i) {
+                return Ok(Some(value_start));
             }
-            i += 1;
-            let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-            let (value_tag, bytes) = match decoded {
-                None => Err(Error::from(ErrorKind::InvalidInput)),
-                Some(v) => Result::Ok(v),
-            }?;
-            c += bytes + (value_tag >> TAG_SIZE);
         }
-        return Ok(Some(start));
+        Ok(None)
+    } else {
+        // Nothing to iterate over, as long as the value is whole
+        payload(buf, start + bytes, len)?;
+        Ok(None)
     }
-
-    Ok(None)
 }
@@ -0,0 +1,368 @@
+use crate::error::BipfError;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// Containers with more members than this are stored as bitmaps.
+const ARRAY_MAX: usize = 4096;
+/// Number of u64 words in a bitmap container, one bit for each of the 65536 low values.
+const BITMAP_WORDS: usize = 1024;
+
+const ARRAY_CONTAINER: u8 = 0;
+const BITMAP_CONTAINER: u8 = 1;
+
+/// A compressed set of u32, such as the sequence numbers of the records an index matches.
+///
+/// Values are grouped by their high 16 bits, in the manner of roaring bitmaps. Each group is
+/// a sorted array of the low 16 bits while it is small, and a 65536-bit bitmap once it holds
+/// more than 4096 values, so sparse and dense sets both stay small.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitset {
+    /// Sorted by their key, the high 16 bits of their values.
+    containers: Vec<(u16, Container)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Container {
+    Array(Vec<u16>),
+    Bitmap(Box<[u64; BITMAP_WORDS]>),
+}
+
+impl Bitset {
+    pub fn new() -> Bitset {
+        Bitset::default()
+    }
+
+    /// Adds `value`, returns `false` if it was already there.
+    pub fn insert(&mut self, value: u32) -> bool {
+        let (key, low) = split(value);
+        let i = match self.containers.binary_search_by_key(&key, |(k, _)| *k) {
+            Ok(i) => i,
+            Err(i) => {
+                self.containers
+                    .insert(i, (key, Container::Array(Vec::new())));
+                i
+            }
+        };
+
+        let container = &mut self.containers[i].1;
+        match container {
+            Container::Array(values) => match values.binary_search(&low) {
+                Ok(_) => false,
+                Err(at) => {
+                    values.insert(at, low);
+                    if values.len() > ARRAY_MAX {
+                        *container = to_bitmap(values);
+                    }
+                    true
+                }
+            },
+            Container::Bitmap(words) => {
+                let (word, bit) = (low as usize / 64, 1u64 << (low % 64));
+                let added = words[word] & bit == 0;
+                words[word] |= bit;
+                added
+            }
+        }
+    }
+
+    pub fn contains(&self, value: u32) -> bool {
+        let (key, low) = split(value);
+        match self.containers.binary_search_by_key(&key, |(k, _)| *k) {
+            Ok(i) => self.containers[i].1.contains(low),
+            Err(_) => false,
+        }
+    }
+
+    /// Number of values in the set.
+    pub fn len(&self) -> u64 {
+        self.containers.iter().map(|(_, c)| c.len() as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+    }
+
+    /// The values in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.containers.iter().flat_map(|(key, container)| {
+            let high = (*key as u32) << 16;
+            container.lows().map(move |low| high | low as u32)
+        })
+    }
+
+    /// Values in both sets, to combine the results of several indexes.
+    pub fn and(&self, other: &Bitset) -> Bitset {
+        let mut containers = Vec::new();
+        for (key, container) in &self.containers {
+            let other = match other.containers.binary_search_by_key(key, |(k, _)| *k) {
+                Ok(i) => &other.containers[i].1,
+                Err(_) => continue,
+            };
+            let both = match (container, other) {
+                (Container::Bitmap(a), Container::Bitmap(b)) => {
+                    let mut words = Box::new([0u64; BITMAP_WORDS]);
+                    for (w, (a, b)) in words.iter_mut().zip(a.iter().zip(b.iter())) {
+                        *w = a & b;
+                    }
+                    shrink(words)
+                }
+                (Container::Array(values), other) | (other, Container::Array(values)) => {
+                    Container::Array(
+                        values
+                            .iter()
+                            .copied()
+                            .filter(|v| other.contains(*v))
+                            .collect(),
+                    )
+                }
+            };
+            if both.len() > 0 {
+                containers.push((*key, both));
+            }
+        }
+        Bitset { containers }
+    }
+
+    /// Values in either set.
+    pub fn or(&self, other: &Bitset) -> Bitset {
+        let mut union = self.clone();
+        for (key, container) in &other.containers {
+            match union.containers.binary_search_by_key(key, |(k, _)| *k) {
+                Ok(i) => {
+                    let mine = &mut union.containers[i].1;
+                    match (&mut *mine, container) {
+                        (Container::Bitmap(a), Container::Bitmap(b)) => {
+                            for (a, b) in a.iter_mut().zip(b.iter()) {
+                                *a |= b;
+                            }
+                        }
+                        _ => {
+                            let high = (*key as u32) << 16;
+                            for low in container.lows() {
+                                union.insert(high | low as u32);
+                            }
+                        }
+                    }
+                }
+                Err(i) => union.containers.insert(i, (*key, container.clone())),
+            }
+        }
+        union
+    }
+
+    /// Appends the serialized set to `out`, all integers little-endian: the number of
+    /// containers as a u32, then for each its key as a u16, its kind as a byte, and either
+    /// a u32 count followed by that many u16 or the 1024 u64 words of its bitmap.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.containers.len() as u32).to_le_bytes());
+        for (key, container) in &self.containers {
+            out.extend_from_slice(&key.to_le_bytes());
+            match container {
+                Container::Array(values) => {
+                    out.push(ARRAY_CONTAINER);
+                    out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+                    for v in values {
+                        out.extend_from_slice(&v.to_le_bytes());
+                    }
+                }
+                Container::Bitmap(words) => {
+                    out.push(BITMAP_CONTAINER);
+                    for w in words.iter() {
+                        out.extend_from_slice(&w.to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads a set written by `write` at the start of `buf`, returns it with the number of
+    /// bytes it took.
+    pub fn read(buf: &[u8]) -> Result<(Bitset, usize)> {
+        let mut c = 0;
+        let count = u32::from_le_bytes(take(buf, &mut c)?);
+        let mut containers: Vec<(u16, Container)> = Vec::new();
+        for _ in 0..count {
+            let key = u16::from_le_bytes(take(buf, &mut c)?);
+            if containers.last().is_some_and(|(last, _)| *last >= key) {
+                return Err(corrupt(c));
+            }
+            let [kind] = take(buf, &mut c)?;
+            let container = match kind {
+                ARRAY_CONTAINER => {
+                    let len = u32::from_le_bytes(take(buf, &mut c)?) as usize;
+                    if len == 0 || len > ARRAY_MAX {
+                        return Err(corrupt(c));
+                    }
+                    let mut values = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        values.push(u16::from_le_bytes(take(buf, &mut c)?));
+                    }
+                    if values.windows(2).any(|w| w[0] >= w[1]) {
+                        return Err(corrupt(c));
+                    }
+                    Container::Array(values)
+                }
+                BITMAP_CONTAINER => {
+                    let mut words = Box::new([0u64; BITMAP_WORDS]);
+                    for w in words.iter_mut() {
+                        *w = u64::from_le_bytes(take(buf, &mut c)?);
+                    }
+                    // `write` only keeps bitmaps too full for an array
+                    let bitmap = Container::Bitmap(words);
+                    if bitmap.len() <= ARRAY_MAX {
+                        return Err(corrupt(c));
+                    }
+                    bitmap
+                }
+                _ => return Err(corrupt(c)),
+            };
+            containers.push((key, container));
+        }
+        Ok((Bitset { containers }, c))
+    }
+}
+
+impl FromIterator<u32> for Bitset {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> Self {
+        let mut set = Bitset::new();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl Container {
+    fn contains(&self, low: u16) -> bool {
+        match self {
+            Container::Array(values) => values.binary_search(&low).is_ok(),
+            Container::Bitmap(words) => words[low as usize / 64] & (1 << (low % 64)) != 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Container::Array(values) => values.len(),
+            Container::Bitmap(words) => words.iter().map(|w| w.count_ones() as usize).sum(),
+        }
+    }
+
+    fn lows(&self) -> Box<dyn Iterator<Item = u16> + '_> {
+        match self {
+            Container::Array(values) => Box::new(values.iter().copied()),
+            Container::Bitmap(words) => Box::new(
+                (0..=u16::MAX)
+                    .filter(move |low| words[*low as usize / 64] & (1 << (low % 64)) != 0),
+            ),
+        }
+    }
+}
+
+fn split(value: u32) -> (u16, u16) {
+    ((value >> 16) as u16, value as u16)
+}
+
+fn to_bitmap(values: &[u16]) -> Container {
+    let mut words = Box::new([0u64; BITMAP_WORDS]);
+    for low in values {
+        words[*low as usize / 64] |= 1 << (low % 64);
+    }
+    Container::Bitmap(words)
+}
+
+/// Keeps a bitmap only while it is worth it.
+fn shrink(words: Box<[u64; BITMAP_WORDS]>) -> Container {
+    let bitmap = Container::Bitmap(words);
+    if bitmap.len() > ARRAY_MAX {
+        bitmap
+    } else {
+        Container::Array(bitmap.lows().collect())
+    }
+}
+
+fn take<const N: usize>(buf: &[u8], c: &mut usize) -> Result<[u8; N]> {
+    match buf.get(*c..*c + N) {
+        Some(bytes) => {
+            *c += N;
+            Ok(bytes.try_into().unwrap())
+        }
+        None => Err(corrupt(*c)),
+    }
+}
+
+fn corrupt(offset: usize) -> BipfError {
+    BipfError::Message(format!("corrupt bitset at byte {}", offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitset::*;
+
+    #[test]
+    fn test_bitset_sparse_and_dense() {
+        let sparse: Bitset = [3, 70_000, 1 << 31].into_iter().collect();
+        // Every other value of the first group, more than an array holds
+        let dense: Bitset = (0..20_000).map(|i| i * 2).collect();
+
+        assert_eq!(sparse.len(), 3);
+        assert!(sparse.contains(70_000) && !sparse.contains(70_001));
+        assert_eq!(
+            sparse.iter().collect::<Vec<u32>>(),
+            vec![3, 70_000, 1 << 31]
+        );
+        assert_eq!(dense.len(), 20_000);
+        assert!(dense.contains(39_998) && !dense.contains(39_999));
+        assert!(dense.iter().eq((0..20_000).map(|i| i * 2)));
+
+        let mut set = dense.clone();
+        assert!(!set.insert(4));
+        assert!(set.insert(5));
+        assert_eq!(set.len(), 20_001);
+        assert!(Bitset::new().is_empty());
+    }
+
+    #[test]
+    fn test_bitset_and_or() {
+        let evens: Bitset = (0..100_000).filter(|i| i % 2 == 0).collect();
+        let threes: Bitset = (0..100_000).filter(|i| i % 3 == 0).collect();
+        let few: Bitset = [6, 7, 99_996].into_iter().collect();
+
+        assert!(evens
+            .and(&threes)
+            .iter()
+            .eq((0..100_000).filter(|i| i % 6 == 0)));
+        assert!(evens
+            .or(&threes)
+            .iter()
+            .eq((0..100_000).filter(|i| i % 2 == 0 || i % 3 == 0)));
+        assert_eq!(
+            evens.and(&few).iter().collect::<Vec<u32>>(),
+            vec![6, 99_996]
+        );
+        assert_eq!(few.and(&evens), evens.and(&few));
+        assert_eq!(few.or(&evens).len(), 50_001);
+        assert!(few.and(&Bitset::new()).is_empty());
+    }
+
+    #[test]
+    fn test_bitset_write_read() {
+        let set: Bitset = (0..10_000)
+            .map(|i| i * 3)
+            .chain([1 << 20, u32::MAX])
+            .collect();
+        let mut buf = vec![];
+        set.write(&mut buf);
+        buf.push(0xff);
+
+        let (read, len) = Bitset::read(&buf).unwrap();
+        assert_eq!(read, set);
+        assert_eq!(len, buf.len() - 1);
+        assert!(Bitset::read(&buf[..len - 1]).is_err());
+        assert_eq!(Bitset::read(&[0, 0, 0, 0]).unwrap(), (Bitset::new(), 4));
+
+        // An empty bitmap container, which `write` never produces
+        let mut empty = vec![1, 0, 0, 0, 0, 0, BITMAP_CONTAINER];
+        empty.resize(empty.len() + BITMAP_WORDS * 8, 0);
+        assert!(Bitset::read(&empty).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt::{self, Display};
+
+/// Everything that can go wrong while reading or writing BIPF.
+///
+/// Offsets are absolute positions in the buffer being read, pointing at the
+/// tag or byte where the problem was detected.
+#[derive(Debug)]
+pub enum BipfError {
+    /// The buffer ends before the value at `offset` does.
+    Truncated {
+        offset: usize,
+    },
+    /// The tag at `offset` has an unknown type, or a type that is not allowed there.
+    InvalidTag {
+        offset: usize,
+        tag: usize,
+    },
+    /// The string starting at `offset` is not valid UTF-8.
+    InvalidUtf8 {
+        offset: usize,
+    },
+    /// A boolnull payload that is neither empty nor one of the known bytes.
+    InvalidBoolNull {
+        offset: usize,
+    },
+    /// A fixed-size value (int or double) whose declared length does not match its type.
+    InvalidLength {
+        offset: usize,
+    },
+    /// A value declares a length that runs past the end of its parent container.
+    LengthOverflow {
+        offset: usize,
+    },
+    /// Containers are nested deeper than `MAX_DEPTH`.
+    DepthExceeded {
+        offset: usize,
+    },
+    /// Bytes were left over after the value ending at `offset`.
+    TrailingBytes {
+        offset: usize,
+    },
+    /// The output buffer cannot hold the encoded value.
+    BufferTooSmall {
+        needed: usize,
+        available: usize,
+    },
+    /// An integer outside of the i32 range that a double cannot represent exactly.
+    InexactInteger(i128),
+    /// Any other failure, such as a custom serde error.
+    Message(String),
+    Io(std::io::Error),
+}
+
+impl BipfError {
+    /// Where in the buffer the error was detected, if it relates to a position.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            BipfError::Truncated { offset }
+            | BipfError::InvalidTag { offset, tag: _ }
+            | BipfError::InvalidUtf8 { offset }
+            | BipfError::InvalidBoolNull { offset }
+            | BipfError::InvalidLength { offset }
+            | BipfError::LengthOverflow { offset }
+            | BipfError::DepthExceeded { offset }
+            | BipfError::TrailingBytes { offset } => Some(*offset),
+            BipfError::BufferTooSmall { .. }
+            | BipfError::InexactInteger(_)
+            | BipfError::Message(_)
+            | BipfError::Io(_) => None,
+        }
+    }
+}
+
+impl Display for BipfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BipfError::Truncated { offset } => write!(f, "truncated value at offset {}", offset),
+            BipfError::InvalidTag { offset, tag } => {
+                write!(f, "invalid type {} at offset {}", tag, offset)
+            }
+            BipfError::InvalidUtf8 { offset } => {
+                write!(f, "invalid utf-8 string at offset {}", offset)
+            }
+            BipfError::InvalidBoolNull { offset } => {
+                write!(f, "invalid boolnull at offset {}", offset)
+            }
+            BipfError::InvalidLength { offset } => {
+                write!(f, "invalid length for type at offset {}", offset)
+            }
+            BipfError::LengthOverflow { offset } => {
+                write!(f, "value at offset {} overflows its container", offset)
+            }
+            BipfError::DepthExceeded { offset } => {
+                write!(f, "maximum nesting depth exceeded at offset {}", offset)
+            }
+            BipfError::TrailingBytes { offset } => {
+                write!(f, "trailing bytes after value at offset {}", offset)
+            }
+            BipfError::BufferTooSmall { needed, available } => write!(
+                f,
+                "buffer too small, {} bytes needed but {} available",
+                needed, available
+            ),
+            BipfError::InexactInteger(v) => {
+                write!(f, "integer {} cannot be encoded exactly as a double", v)
+            }
+            BipfError::Message(message) => f.write_str(message),
+            BipfError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BipfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BipfError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BipfError {
+    fn from(e: std::io::Error) -> Self {
+        BipfError::Io(e)
+    }
+}
+
+impl From<BipfError> for std::io::Error {
+    fn from(e: BipfError) -> Self {
+        match e {
+            BipfError::Io(e) => e,
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl serde::ser::Error for BipfError {
+    fn custom<T: Display>(msg: T) -> Self {
+        BipfError::Message(msg.to_string())
+    }
+}
+
+impl serde::de::Error for BipfError {
+    fn custom<T: Display>(msg: T) -> Self {
+        BipfError::Message(msg.to_string())
+    }
+}
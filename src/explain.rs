@@ -0,0 +1,271 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
+use std::fmt::Write;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// Longest preview, in characters, before it is cut with an ellipsis.
+const PREVIEW_SIZE: usize = 32;
+
+/// One encoded value, as found by `explain_nodes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainNode {
+    /// Offset of the tag.
+    pub offset: usize,
+    /// The raw tag varint.
+    pub tag: Vec<u8>,
+    pub field_type: usize,
+    /// Length of the payload.
+    pub len: usize,
+    /// Container nesting, 0 for the value at `start`.
+    pub depth: usize,
+    /// The key this value is stored under, inside an object.
+    pub key: Option<String>,
+    /// A short rendering of scalars. Containers have none, their children follow them.
+    pub preview: Option<String>,
+}
+
+/// Lists every value nested in the one at `start`, in encoding order.
+pub fn explain_nodes(buf: &[u8], start: usize) -> Result<Vec<ExplainNode>> {
+    let mut nodes = Vec::new();
+    explain_at(buf, start, buf.len(), 0, None, &mut nodes)?;
+    Ok(nodes)
+}
+
+/// Renders the value at `start` as an annotated dump, one line per value.
+///
+/// Unlike `explain_nodes`, a malformed buffer is not an error: the values read before the
+/// problem are listed, followed by a line describing it.
+pub fn explain(buf: &[u8], start: usize) -> String {
+    let mut nodes = Vec::new();
+    let result = explain_at(buf, start, buf.len(), 0, None, &mut nodes);
+
+    let mut s = String::new();
+    for node in &nodes {
+        let tag: Vec<String> = node.tag.iter().map(|b| format!("{:02x}", b)).collect();
+        let _ = write!(
+            s,
+            "{:>8}  {:<11} {}",
+            node.offset,
+            tag.join(" "),
+            "  ".repeat(node.depth)
+        );
+        if let Some(key) = &node.key {
+            let _ = write!(s, "{}: ", quote(key));
+        }
+        let _ = write!(s, "{} len={}", type_name(node.field_type), node.len);
+        if let Some(preview) = &node.preview {
+            let _ = write!(s, " {}", preview);
+        }
+        s.push('\n');
+    }
+    if let Err(e) = result {
+        let _ = writeln!(s, "error: {}", e);
+    }
+    s
+}
+
+fn type_name(field_type: usize) -> &'static str {
+    match field_type {
+        STRING => "string",
+        BUFFER => "buffer",
+        INT => "int",
+        DOUBLE => "double",
+        ARRAY => "array",
+        OBJECT => "object",
+        BOOLNULL => "boolnull",
+        _ => "extension",
+    }
+}
+
+fn explain_at(
+    buf: &[u8],
+    start: usize,
+    end: usize,
+    depth: usize,
+    key: Option<String>,
+    nodes: &mut Vec<ExplainNode>,
+) -> Result<usize> {
+    let (field_type, len, bytes) = read_tag_within(buf, start, end)?;
+    let value_start = start + bytes;
+    let value_end = value_start + len;
+    let payload = &buf[value_start..value_end];
+
+    let preview = match field_type {
+        STRING => match std::str::from_utf8(payload) {
+            Ok(s) => Some(quote(s)),
+            Err(e) => {
+                return Err(BipfError::InvalidUtf8 {
+                    offset: value_start + e.valid_up_to(),
+                })
+            }
+        },
+        BUFFER => Some(hex_preview(payload)),
+        INT => match payload.try_into() {
+            Ok(b) => Some(i32::from_le_bytes(b).to_string()),
+            Err(_) => return Err(BipfError::InvalidLength { offset: start }),
+        },
+        DOUBLE => match payload.try_into() {
+            Ok(b) => Some(f64::from_le_bytes(b).to_string()),
+            Err(_) => return Err(BipfError::InvalidLength { offset: start }),
+        },
+        BOOLNULL => match payload {
+            [] => Some(String::from("null")),
+            [0] => Some(String::from("false")),
+            [1] => Some(String::from("true")),
+            [UNDEFINED] => Some(String::from("undefined")),
+            _ => {
+                return Err(BipfError::InvalidBoolNull {
+                    offset: value_start,
+                })
+            }
+        },
+        EXTENSION => {
+            let (subtype, data) = split_extension(buf, value_start, len)?;
+            Some(format!("subtype={} {}", subtype, hex_preview(data)))
+        }
+        ARRAY | OBJECT if depth >= MAX_DEPTH => {
+            return Err(BipfError::DepthExceeded { offset: start })
+        }
+        _ => None,
+    };
+
+    nodes.push(ExplainNode {
+        offset: start,
+        tag: buf[start..value_start].to_vec(),
+        field_type,
+        len,
+        depth,
+        key,
+        preview,
+    });
+
+    if field_type != ARRAY && field_type != OBJECT {
+        return Ok(value_end);
+    }
+
+    let mut c = value_start;
+    while c < value_end {
+        c = match field_type {
+            OBJECT => {
+                let (key_type, key_len, key_bytes) = read_tag_within(buf, c, value_end)?;
+                let key_start = c + key_bytes;
+                let key = match (
+                    key_type,
+                    std::str::from_utf8(&buf[key_start..key_start + key_len]),
+                ) {
+                    (STRING, Ok(k)) => String::from(k),
+                    (STRING, Err(e)) => {
+                        return Err(BipfError::InvalidUtf8 {
+                            offset: key_start + e.valid_up_to(),
+                        })
+                    }
+                    _ => {
+                        return Err(BipfError::InvalidTag {
+                            offset: c,
+                            tag: key_type,
+                        })
+                    }
+                };
+                let value = key_start + key_len;
+                if value == value_end {
+                    return Err(BipfError::Truncated { offset: value });
+                }
+                explain_at(buf, value, value_end, depth + 1, Some(key), nodes)?
+            }
+            _ => explain_at(buf, c, value_end, depth + 1, None, nodes)?,
+        };
+    }
+
+    Ok(value_end)
+}
+
+fn quote(s: &str) -> String {
+    let mut chars = s.chars();
+    let head: String = chars.by_ref().take(PREVIEW_SIZE).collect();
+    match chars.next() {
+        Some(_) => format!("{:?}…", head),
+        None => format!("{:?}", head),
+    }
+}
+
+fn hex_preview(bytes: &[u8]) -> String {
+    let hex: String = bytes
+        .iter()
+        .take(PREVIEW_SIZE / 2)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if bytes.len() > PREVIEW_SIZE / 2 {
+        format!("<{}…>", hex)
+    } else {
+        format!("<{}>", hex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::explain::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_explain_nodes() {
+        let buf = json!({"a": [1, "x"], "b": null}).to_bipf().unwrap();
+        let nodes = explain_nodes(&buf, 0).unwrap();
+
+        let summary: Vec<(usize, usize, usize, Option<&str>)> = nodes
+            .iter()
+            .map(|n| (n.offset, n.field_type, n.depth, n.key.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, OBJECT, 0, None),
+                (3, ARRAY, 1, Some("a")),
+                (4, INT, 2, None),
+                (9, STRING, 2, None),
+                (13, BOOLNULL, 1, Some("b")),
+            ]
+        );
+        assert_eq!(nodes[0].tag, vec![0x6d]);
+        assert_eq!(nodes[2].preview.as_deref(), Some("1"));
+        assert_eq!(nodes[3].preview.as_deref(), Some("\"x\""));
+        assert_eq!(nodes[4].preview.as_deref(), Some("null"));
+    }
+
+    #[test]
+    fn test_explain_render() {
+        let buf = json!({"a": [1, "x"], "b": null}).to_bipf().unwrap();
+
+        assert_eq!(
+            explain(&buf, 0),
+            [
+                "       0  6d          object len=13",
+                "       3  3c            \"a\": array len=7",
+                "       4  22              int len=4 1",
+                "       9  08              string len=1 \"x\"",
+                "      13  06            \"b\": boolnull len=0 null",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_explain_malformed() {
+        // {"a": "bc"} where the value claims 3 bytes and spills out of the object
+        let buf: Vec<u8> = vec![0x2d, 0x08, 0x61, 0x18, 0x62, 0x63, 0x64];
+
+        assert!(explain_nodes(&buf, 0).is_err());
+        assert_eq!(
+            explain(&buf, 0),
+            [
+                "       0  2d          object len=5",
+                "error: value at offset 3 overflows its container",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
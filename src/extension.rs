@@ -0,0 +1,204 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use integer_encoding::VarInt;
+use serde_json::{Map, Value};
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// A type stored under the `EXTENSION` tag.
+///
+/// The payload of an extension value is its `SUBTYPE` as a varint followed by the bytes
+/// written by `write_payload`. Implementing the trait is all it takes to register a type:
+/// values of other subtypes are left alone and kept as opaque bytes.
+pub trait Extension: Sized {
+    const SUBTYPE: u64;
+
+    fn write_payload(&self, out: &mut Vec<u8>);
+
+    fn read_payload(data: &[u8]) -> Result<Self>;
+}
+
+/// Encodes `value` as a standalone extension value, tag included.
+pub fn encode_extension<T: Extension>(value: &T) -> Vec<u8> {
+    let mut data = Vec::new();
+    value.write_payload(&mut data);
+    let mut buf = Vec::new();
+    write_extension(&mut buf, T::SUBTYPE, &data);
+    buf
+}
+
+/// Decodes the extension value at `start` as a `T`.
+///
+/// Fails with `InvalidTag` if the value is not an extension or is one of another subtype.
+pub fn decode_extension<T: Extension>(buf: &[u8], start: usize) -> Result<T> {
+    let (field_type, len, bytes) = read_tag(buf, start)?;
+    if field_type != EXTENSION {
+        return Err(BipfError::InvalidTag {
+            offset: start,
+            tag: field_type,
+        });
+    }
+
+    match split_extension(buf, start + bytes, len)? {
+        (subtype, data) if subtype == T::SUBTYPE => T::read_payload(data),
+        _ => Err(BipfError::InvalidTag {
+            offset: start,
+            tag: field_type,
+        }),
+    }
+}
+
+/// Splits the payload of the extension at `start` into its subtype and data.
+pub fn split_extension(buf: &[u8], start: usize, len: usize) -> Result<(u64, &[u8])> {
+    let payload = payload(buf, start, len)?;
+    match u64::decode_var(payload) {
+        Some((subtype, bytes)) => Ok((subtype, &payload[bytes..])),
+        None => Err(BipfError::InvalidLength { offset: start }),
+    }
+}
+
+pub(crate) fn write_extension(buf: &mut Vec<u8>, subtype: u64, data: &[u8]) {
+    write_tag(buf, EXTENSION, extension_length(subtype, data));
+    buf.extend_from_slice(&subtype.encode_var_vec());
+    buf.extend_from_slice(data);
+}
+
+/// Payload length of an extension, subtype included.
+pub(crate) fn extension_length(subtype: u64, data: &[u8]) -> usize {
+    subtype.required_space() + data.len()
+}
+
+/// `{"type": "Extension", "subtype": n, "data": [...]}`, the JSON form of any extension.
+///
+/// Like Node buffers, `BipfValue::from_json` turns it back into the same bytes.
+pub(crate) fn extension_to_json(subtype: u64, data: &[u8]) -> Value {
+    let mut map = Map::with_capacity(3);
+    map.insert(String::from("type"), Value::from("Extension"));
+    map.insert(String::from("subtype"), Value::from(subtype));
+    map.insert(
+        String::from("data"),
+        Value::Array(data.iter().map(|b| Value::from(*b)).collect()),
+    );
+    Value::Object(map)
+}
+
+/// Recognizes the form produced by `extension_to_json`.
+pub(crate) fn extension_from_json(o: &Map<String, Value>) -> Option<(u64, Vec<u8>)> {
+    if o.len() != 3 || o.get("type")?.as_str()? != "Extension" {
+        return None;
+    }
+    let subtype = o.get("subtype")?.as_u64()?;
+    let data = o
+        .get("data")?
+        .as_array()?
+        .iter()
+        .map(|b| u8::try_from(b.as_u64()?).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((subtype, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::error::*;
+    use crate::extension::*;
+    use crate::json::*;
+    use crate::validate::*;
+    use crate::value::*;
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq)]
+    struct Timestamp(i64);
+
+    impl Extension for Timestamp {
+        const SUBTYPE: u64 = 1;
+
+        fn write_payload(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&self.0.to_le_bytes());
+        }
+
+        fn read_payload(data: &[u8]) -> Result<Self> {
+            match data.try_into() {
+                Ok(b) => Ok(Timestamp(i64::from_le_bytes(b))),
+                Err(_) => Err(BipfError::Message(String::from("bad timestamp"))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_extension_round_trip() {
+        let buf = encode_extension(&Timestamp(1_650_000_000_000));
+
+        assert_eq!(buf[0], (9 << TAG_SIZE | EXTENSION) as u8);
+        assert_eq!(buf[1], 1);
+        assert_eq!(
+            decode_extension::<Timestamp>(&buf, 0).unwrap(),
+            Timestamp(1_650_000_000_000)
+        );
+        assert!(matches!(
+            decode_extension::<Timestamp>(&json!(1).to_bipf().unwrap(), 0),
+            Err(BipfError::InvalidTag { offset: 0, tag: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_extension_is_opaque() {
+        // {"a": <extension 300 with data [1, 2]>, "b": true}
+        let mut ext = Vec::new();
+        write_extension(&mut ext, 300, &[1, 2]);
+        let value = BipfValue::Object(
+            [
+                (String::from("a"), BipfValue::Extension(300, vec![1, 2])),
+                (String::from("b"), BipfValue::Bool(true)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let buf = value.to_bipf().unwrap();
+
+        assert_eq!(value.encoding_length(), buf.len());
+        assert_eq!(validate(&buf).unwrap().values, 5);
+        assert_eq!(decode_value(&buf, 0).unwrap(), value);
+        let a = seek_key(&buf, Some(0), String::from("a")).unwrap().unwrap();
+        assert_eq!(&buf[a..a + ext.len()], &ext[..]);
+        let b = seek_key(&buf, Some(0), String::from("b")).unwrap().unwrap();
+        assert_eq!(decode_rec(&buf, b).unwrap(), json!(true));
+
+        let as_json = decode(&buf).unwrap();
+        assert_eq!(
+            as_json,
+            json!({"a": {"type": "Extension", "subtype": 300, "data": [1, 2]}, "b": true})
+        );
+        // Plain data of that shape stays an object unless asked otherwise
+        let plain = BipfValue::from_json(&as_json, &JsonOptions::default());
+        assert_eq!(plain.to_bipf().unwrap(), as_json.to_bipf().unwrap());
+        let tagged = JsonOptions {
+            extensions: ExtensionMapping::TaggedObject,
+            ..JsonOptions::default()
+        };
+        let back = BipfValue::from_json(&as_json, &tagged);
+        assert_eq!(back.to_bipf().unwrap(), buf);
+        let refuse = JsonOptions {
+            extensions: ExtensionMapping::Error,
+            ..JsonOptions::default()
+        };
+        assert!(decode_with(&buf, 0, &refuse).is_err());
+        assert!(to_json_string_with(&buf, 0, JsonStyle::Compact, &refuse).is_err());
+        assert!(matches!(
+            decode_extension::<Timestamp>(&buf, a),
+            Err(BipfError::InvalidTag { .. })
+        ));
+    }
+
+    #[test]
+    fn test_extension_without_subtype() {
+        assert!(matches!(
+            validate(&[0x07]),
+            Err(BipfError::InvalidLength { offset: 1 })
+        ));
+        assert!(matches!(
+            decode(&[0x0f, 0x80]),
+            Err(BipfError::InvalidLength { offset: 1 })
+        ));
+    }
+}
@@ -0,0 +1,650 @@
+use crate::bipf::*;
+use crate::bitset::Bitset;
+use crate::error::BipfError;
+use crate::log::{Log, LogIter, MmapLog, MmapLogIter};
+use crate::validate::validate;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+const EQUALITY_MAGIC: &[u8; 4] = b"BEQ1";
+const PREFIX_MAGIC: &[u8; 4] = b"BPX1";
+/// Longest prefix a `PrefixIndex` keeps, the size of a u32.
+pub const MAX_PREFIX_SIZE: usize = 4;
+
+/// The records of a log whose value at a path is a given value.
+///
+/// Records are numbered in log order from 0, deleted ones included so the numbers never
+/// shift, and the index keeps the numbers of those that match in a `Bitset`. Matching is a
+/// raw comparison of the encoded value found by the path with the target, tag included, so
+/// `1` and `1.0` are different values.
+///
+/// The index remembers how far into the log it got: `update` only scans the records appended
+/// since, and `save` and `open` keep that across runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EqualityIndex {
+    path: SeekPath,
+    value: Vec<u8>,
+    matches: Bitset,
+    /// Number of records scanned so far, the sequence number of the next one.
+    seq: u32,
+    /// Log offset the next scan starts at.
+    offset: u64,
+}
+
+impl EqualityIndex {
+    /// An empty index of the records with the encoded `value` at `path`.
+    pub fn new(path: SeekPath, value: &[u8]) -> Result<EqualityIndex> {
+        validate(value)?;
+        Ok(EqualityIndex {
+            path,
+            value: value.to_vec(),
+            matches: Bitset::new(),
+            seq: 0,
+            offset: 0,
+        })
+    }
+
+    /// Loads the index saved at `file`, or starts an empty one when there is none.
+    ///
+    /// Fails if the saved index was built for another path or value.
+    pub fn open<P: AsRef<Path>>(file: P, path: SeekPath, value: &[u8]) -> Result<EqualityIndex> {
+        let mut index = EqualityIndex::new(path, value)?;
+        let buf = match fs::read(file) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut c = 0;
+        if take(&buf, &mut c, EQUALITY_MAGIC.len())? != EQUALITY_MAGIC {
+            return Err(corrupt_index());
+        }
+        index.offset = u64::from_le_bytes(take_array(&buf, &mut c)?);
+        index.seq = u32::from_le_bytes(take_array(&buf, &mut c)?);
+        if take_sized(&buf, &mut c)? != index.path.encode().as_slice()
+            || take_sized(&buf, &mut c)? != index.value.as_slice()
+        {
+            return Err(BipfError::Message(String::from(
+                "index was built for another path or value",
+            )));
+        }
+        let (matches, len) = Bitset::read(&buf[c..])?;
+        if c + len != buf.len() {
+            return Err(corrupt_index());
+        }
+        index.matches = matches;
+        Ok(index)
+    }
+
+    /// Writes the index to `file`, replacing it whole so a crash leaves the previous version.
+    pub fn save<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        let mut buf = Vec::from(&EQUALITY_MAGIC[..]);
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        put_sized(&mut buf, &self.path.encode());
+        put_sized(&mut buf, &self.value);
+        self.matches.write(&mut buf);
+        save_atomic(file.as_ref(), &buf)
+    }
+
+    /// Indexes the records appended to `log` since the last update, returns how many.
+    pub fn update(&mut self, log: &Log) -> Result<u32> {
+        self.index_records(log.iter_from(self.offset), LogIter::offset)
+    }
+
+    /// `update` from a memory-mapped log.
+    pub fn update_mapped(&mut self, log: &MmapLog) -> Result<u32> {
+        self.index_records(log.iter_from(self.offset), MmapLogIter::offset)
+    }
+
+    /// Sequence numbers of the matching records.
+    pub fn matches(&self) -> &Bitset {
+        &self.matches
+    }
+
+    /// Number of records indexed.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Log offset the next update starts at.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether `record` has the target value at the path.
+    pub fn is_match(&self, record: &[u8]) -> Result<bool> {
+        has_value(&self.path, record, &self.value)
+    }
+
+    /// Deleted records match nothing but still take a sequence number.
+    fn index_records<R, I>(&mut self, mut records: I, next_offset: fn(&I) -> u64) -> Result<u32>
+    where
+        R: AsRef<[u8]>,
+        I: Iterator<Item = Result<(u64, Option<R>)>>,
+    {
+        let mut count = 0;
+        while let Some(record) = records.next() {
+            if let (_, Some(record)) = record? {
+                if self.is_match(record.as_ref())? {
+                    self.matches.insert(self.seq);
+                }
+            }
+            self.seq += 1;
+            self.offset = next_offset(&records);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// The first bytes of the string at a path, for each record of a log.
+///
+/// Each record gets a u32 holding the first `prefix_size` bytes of its string, little-endian
+/// and padded with zeros. Records without a string at the path get 0, like an empty string,
+/// and so do deleted ones. The index also keeps the log offset of each record. A few bytes
+/// are enough to tell most keys or authors apart, so `lookup` compares prefixes first and
+/// only reads the few records that share one, straight from their offsets, to check their
+/// whole value.
+///
+/// Like `EqualityIndex`, the index is updated with the records appended since the last
+/// update, and `save` and `open` keep it across runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixIndex {
+    path: SeekPath,
+    prefix_size: usize,
+    /// Indexed by sequence number.
+    prefixes: Vec<u32>,
+    /// Log offset of each record, indexed by sequence number.
+    offsets: Vec<u64>,
+    offset: u64,
+}
+
+impl PrefixIndex {
+    /// An empty index of the first `prefix_size` bytes, at most `MAX_PREFIX_SIZE`, of the
+    /// strings at `path`.
+    pub fn new(path: SeekPath, prefix_size: usize) -> Result<PrefixIndex> {
+        if prefix_size == 0 || prefix_size > MAX_PREFIX_SIZE {
+            return Err(BipfError::Message(format!(
+                "invalid prefix size {}",
+                prefix_size
+            )));
+        }
+        Ok(PrefixIndex {
+            path,
+            prefix_size,
+            prefixes: Vec::new(),
+            offsets: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    /// Loads the index saved at `file`, or starts an empty one when there is none.
+    ///
+    /// Fails if the saved index was built for another path or prefix size.
+    pub fn open<P: AsRef<Path>>(
+        file: P,
+        path: SeekPath,
+        prefix_size: usize,
+    ) -> Result<PrefixIndex> {
+        let mut index = PrefixIndex::new(path, prefix_size)?;
+        let buf = match fs::read(file) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut c = 0;
+        if take(&buf, &mut c, PREFIX_MAGIC.len())? != PREFIX_MAGIC {
+            return Err(corrupt_index());
+        }
+        index.offset = u64::from_le_bytes(take_array(&buf, &mut c)?);
+        let size = u32::from_le_bytes(take_array(&buf, &mut c)?) as usize;
+        if size != index.prefix_size || take_sized(&buf, &mut c)? != index.path.encode().as_slice()
+        {
+            return Err(BipfError::Message(String::from(
+                "index was built for another path or prefix size",
+            )));
+        }
+        let count = u32::from_le_bytes(take_array(&buf, &mut c)?) as usize;
+        if buf.len() - c != count * (4 + 8) {
+            return Err(corrupt_index());
+        }
+        let (prefixes, offsets) = buf[c..].split_at(count * 4);
+        index.prefixes = prefixes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        index.offsets = offsets
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok(index)
+    }
+
+    /// Writes the index to `file`, replacing it whole so a crash leaves the previous version.
+    ///
+    /// The file is a header with the offset, the prefix size, the encoded path and the
+    /// number of records, followed by the prefixes as a flat array of little-endian u32 and
+    /// the record offsets as one of little-endian u64.
+    pub fn save<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        let mut buf = Vec::from(&PREFIX_MAGIC[..]);
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&(self.prefix_size as u32).to_le_bytes());
+        put_sized(&mut buf, &self.path.encode());
+        buf.extend_from_slice(&(self.prefixes.len() as u32).to_le_bytes());
+        buf.reserve(self.prefixes.len() * (4 + 8));
+        for prefix in &self.prefixes {
+            buf.extend_from_slice(&prefix.to_le_bytes());
+        }
+        for offset in &self.offsets {
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        save_atomic(file.as_ref(), &buf)
+    }
+
+    /// Indexes the records appended to `log` since the last update, returns how many.
+    pub fn update(&mut self, log: &Log) -> Result<u32> {
+        self.index_records(log.iter_from(self.offset), LogIter::offset)
+    }
+
+    /// `update` from a memory-mapped log.
+    pub fn update_mapped(&mut self, log: &MmapLog) -> Result<u32> {
+        self.index_records(log.iter_from(self.offset), MmapLogIter::offset)
+    }
+
+    /// The prefix of each record, by sequence number.
+    pub fn prefixes(&self) -> &[u32] {
+        &self.prefixes
+    }
+
+    /// The log offset of each record, by sequence number.
+    pub fn offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+
+    /// Log offset the next update starts at.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Sequence numbers of the records whose string might be `value`, those with its prefix.
+    pub fn candidates(&self, value: &str) -> Bitset {
+        let prefix = self.prefix_of(value.as_bytes());
+        (0..self.prefixes.len() as u32)
+            .filter(|seq| self.prefixes[*seq as usize] == prefix)
+            .collect()
+    }
+
+    /// Sequence numbers of the records with the string `value` at the path.
+    ///
+    /// The candidates are checked against the records themselves, read from `log`, which
+    /// must be the log the index was built from. Records deleted since are left out.
+    pub fn lookup(&self, log: &Log, value: &str) -> Result<Bitset> {
+        self.verify(|offset| log.get(offset), value)
+    }
+
+    /// `lookup` in a memory-mapped log.
+    pub fn lookup_mapped(&self, log: &MmapLog, value: &str) -> Result<Bitset> {
+        self.verify(|offset| log.get(offset), value)
+    }
+
+    fn prefix_of(&self, bytes: &[u8]) -> u32 {
+        let mut prefix = [0u8; MAX_PREFIX_SIZE];
+        let len = bytes.len().min(self.prefix_size);
+        prefix[..len].copy_from_slice(&bytes[..len]);
+        u32::from_le_bytes(prefix)
+    }
+
+    fn verify<R, F>(&self, get: F, value: &str) -> Result<Bitset>
+    where
+        R: AsRef<[u8]>,
+        F: Fn(u64) -> Result<Option<R>>,
+    {
+        let mut encoded = Vec::with_capacity(value.len() + 2);
+        write_tag(&mut encoded, STRING, value.len());
+        encoded.extend_from_slice(value.as_bytes());
+
+        let mut matches = Bitset::new();
+        for seq in self.candidates(value).iter() {
+            if let Some(record) = get(self.offsets[seq as usize])? {
+                if has_value(&self.path, record.as_ref(), &encoded)? {
+                    matches.insert(seq);
+                }
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Deleted records get 0, like those without a string.
+    fn index_records<R, I>(&mut self, mut records: I, next_offset: fn(&I) -> u64) -> Result<u32>
+    where
+        R: AsRef<[u8]>,
+        I: Iterator<Item = Result<(u64, Option<R>)>>,
+    {
+        let mut count = 0;
+        while let Some(record) = records.next() {
+            let (offset, record) = record?;
+            let prefix = match record {
+                Some(record) => self.string_prefix(record.as_ref())?,
+                None => 0,
+            };
+            self.prefixes.push(prefix);
+            self.offsets.push(offset);
+            self.offset = next_offset(&records);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn string_prefix(&self, record: &[u8]) -> Result<u32> {
+        match self.path.seek(record, 0)? {
+            Some(c) => match read_tag(record, c)? {
+                (STRING, len, bytes) => Ok(self.prefix_of(&record[c + bytes..c + bytes + len])),
+                _ => Ok(0),
+            },
+            None => Ok(0),
+        }
+    }
+}
+
+/// Whether the value at `path` in `record` is the encoded `value`, compared byte for byte.
+fn has_value(path: &SeekPath, record: &[u8], value: &[u8]) -> Result<bool> {
+    match path.seek(record, 0)? {
+        Some(c) => Ok(record.get(c..c + value.len()) == Some(value)),
+        None => Ok(false),
+    }
+}
+
+/// Writes `buf` to a temporary file next to `file` and renames it over `file`.
+fn save_atomic(file: &Path, buf: &[u8]) -> Result<()> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut out = File::create(&tmp)?;
+    out.write_all(buf)?;
+    out.sync_data()?;
+    fs::rename(&tmp, file)?;
+    Ok(())
+}
+
+/// Appends `bytes` prefixed with their length as a little-endian u32.
+fn put_sized(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn take_sized<'a>(buf: &'a [u8], c: &mut usize) -> Result<&'a [u8]> {
+    let len = u32::from_le_bytes(take_array(buf, c)?) as usize;
+    take(buf, c, len)
+}
+
+fn take_array<const N: usize>(buf: &[u8], c: &mut usize) -> Result<[u8; N]> {
+    Ok(take(buf, c, N)?.try_into().unwrap())
+}
+
+fn take<'a>(buf: &'a [u8], c: &mut usize, len: usize) -> Result<&'a [u8]> {
+    match buf.get(*c..*c + len) {
+        Some(bytes) => {
+            *c += len;
+            Ok(bytes)
+        }
+        None => Err(corrupt_index()),
+    }
+}
+
+fn corrupt_index() -> BipfError {
+    BipfError::Message(String::from("corrupt index file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::index::*;
+    use serde_json::json;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, ext: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("bipf-{}-{}.{}", name, std::process::id(), ext));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn post(i: usize) -> Vec<u8> {
+        let kind = match i % 3 {
+            0 => "vote",
+            _ => "post",
+        };
+        json!({"key": format!("%{}", i), "value": {"content": {"type": kind}}})
+            .to_bipf()
+            .unwrap()
+    }
+
+    /// Deletes the record at `offset` the way async-append-only-log does, zeroing its bytes.
+    fn delete(path: &Path, offset: u64, len: usize) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offset + 2)).unwrap();
+        file.write_all(&vec![0; len]).unwrap();
+    }
+
+    fn type_path() -> SeekPath {
+        SeekPath::compile(&["value".into(), "content".into(), "type".into()])
+    }
+
+    #[test]
+    fn test_equality_index_update() {
+        let path = temp_file("equality", "log");
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+        for i in 0..100 {
+            log.append(&post(i)).unwrap();
+        }
+        // A record without the path matches nothing but still takes a number
+        log.append(&json!({"value": {"content": 1}}).to_bipf().unwrap())
+            .unwrap();
+
+        let vote = json!("vote").to_bipf().unwrap();
+        let mut index = EqualityIndex::new(type_path(), &vote).unwrap();
+        assert_eq!(index.update(&log).unwrap(), 101);
+        assert!(index.matches().iter().eq((0..100).step_by(3)));
+
+        for i in 100..110 {
+            log.append(&post(i)).unwrap();
+        }
+        assert_eq!(index.update(&log).unwrap(), 10);
+        assert_eq!(index.update(&log).unwrap(), 0);
+        assert_eq!(index.seq(), 111);
+        let expected: Vec<u32> = (0..100).step_by(3).chain([103, 106, 109]).collect();
+        assert_eq!(index.matches().iter().collect::<Vec<u32>>(), expected);
+
+        log.sync().unwrap();
+        let mapped = MmapLog::open_with_block_size(&path, 512).unwrap();
+        let mut from_map = EqualityIndex::new(type_path(), &vote).unwrap();
+        assert_eq!(from_map.update_mapped(&mapped).unwrap(), 111);
+        assert_eq!(from_map, index);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_equality_index_deleted_records() {
+        let path = temp_file("equality-deleted", "log");
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+        let offsets: Vec<u64> = (0..10).map(|i| log.append(&post(i)).unwrap()).collect();
+        // Two votes gone, 3 in the middle of the log and 9 at its very end
+        delete(&path, offsets[3], post(3).len());
+        delete(&path, offsets[9], post(9).len());
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+
+        let vote = json!("vote").to_bipf().unwrap();
+        let mut index = EqualityIndex::new(type_path(), &vote).unwrap();
+        assert_eq!(index.update(&log).unwrap(), 10);
+        assert_eq!(index.seq(), 10);
+        assert_eq!(index.matches().iter().collect::<Vec<u32>>(), vec![0, 6]);
+
+        // Deleted records are counted, the next update starts after the last of them
+        log.append(&post(12)).unwrap();
+        assert_eq!(index.update(&log).unwrap(), 1);
+        assert_eq!(index.matches().iter().collect::<Vec<u32>>(), vec![0, 6, 10]);
+
+        log.sync().unwrap();
+        let mapped = MmapLog::open_with_block_size(&path, 512).unwrap();
+        let mut from_map = EqualityIndex::new(type_path(), &vote).unwrap();
+        assert_eq!(from_map.update_mapped(&mapped).unwrap(), 11);
+        assert_eq!(from_map, index);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_equality_index_save_open() {
+        let path = temp_file("equality-save", "log");
+        let file = temp_file("equality-save", "index");
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+        for i in 0..50 {
+            log.append(&post(i)).unwrap();
+        }
+
+        let post_value = json!("post").to_bipf().unwrap();
+        let mut index = EqualityIndex::open(&file, type_path(), &post_value).unwrap();
+        assert_eq!(index.seq(), 0);
+        index.update(&log).unwrap();
+        index.save(&file).unwrap();
+
+        // Reopened, it only scans what was appended since
+        for i in 50..60 {
+            log.append(&post(i)).unwrap();
+        }
+        let mut reopened = EqualityIndex::open(&file, type_path(), &post_value).unwrap();
+        assert_eq!(reopened.offset(), index.offset());
+        assert_eq!(reopened.update(&log).unwrap(), 10);
+        assert!(reopened.matches().iter().eq((0..60).filter(|i| i % 3 != 0)));
+
+        let other = json!("vote").to_bipf().unwrap();
+        assert!(EqualityIndex::open(&file, type_path(), &other).is_err());
+        std::fs::write(&file, b"BEQ1").unwrap();
+        assert!(EqualityIndex::open(&file, type_path(), &post_value).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    fn author_path() -> SeekPath {
+        SeekPath::compile(&["value".into(), "author".into()])
+    }
+
+    fn message(author: &str) -> Vec<u8> {
+        json!({"value": {"author": author, "content": {"type": "post"}}})
+            .to_bipf()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_prefix_index_lookup() {
+        let path = temp_file("prefix", "log");
+        let authors = ["@alice", "@alfred", "@bob", "@al", "@alice"];
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+        for author in authors {
+            log.append(&message(author)).unwrap();
+        }
+        log.append(&json!({"value": {"author": 1}}).to_bipf().unwrap())
+            .unwrap();
+
+        let mut index = PrefixIndex::new(author_path(), 3).unwrap();
+        assert_eq!(index.update(&log).unwrap(), 6);
+        assert_eq!(index.prefixes()[0], u32::from_le_bytes(*b"@al\0"));
+        assert_eq!(index.prefixes()[5], 0);
+
+        // "@al" records share the prefix, only the exact ones are kept
+        let alice = index.lookup(&log, "@alice").unwrap();
+        assert_eq!(index.candidates("@alice").len(), 4);
+        assert_eq!(alice.iter().collect::<Vec<u32>>(), vec![0, 4]);
+        assert_eq!(
+            index
+                .lookup(&log, "@al")
+                .unwrap()
+                .iter()
+                .collect::<Vec<u32>>(),
+            vec![3]
+        );
+        assert!(index.lookup(&log, "@carol").unwrap().is_empty());
+
+        log.append(&message("@alice")).unwrap();
+        assert_eq!(index.update(&log).unwrap(), 1);
+        log.sync().unwrap();
+        let mapped = MmapLog::open_with_block_size(&path, 512).unwrap();
+        let alice = index.lookup_mapped(&mapped, "@alice").unwrap();
+        assert_eq!(alice.iter().collect::<Vec<u32>>(), vec![0, 4, 6]);
+
+        assert!(PrefixIndex::new(author_path(), 5).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_index_deleted_records() {
+        let path = temp_file("prefix-deleted", "log");
+        let authors = ["@alice", "@bob", "@alice", "@alice", "@al"];
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+        let offsets: Vec<u64> = authors
+            .iter()
+            .map(|author| log.append(&message(author)).unwrap())
+            .collect();
+        delete(&path, offsets[2], message("@alice").len());
+
+        let mut index = PrefixIndex::new(author_path(), 4).unwrap();
+        assert_eq!(index.update(&log).unwrap(), 5);
+        assert_eq!(index.prefixes()[2], 0);
+        assert_eq!(index.offsets(), offsets);
+        let alice = index.lookup(&log, "@alice").unwrap();
+        assert_eq!(alice.iter().collect::<Vec<u32>>(), vec![0, 3]);
+
+        // Deleted after being indexed, the record is still a candidate but not a match
+        delete(&path, offsets[0], message("@alice").len());
+        assert_eq!(index.candidates("@alice").len(), 2);
+        let alice = index.lookup(&log, "@alice").unwrap();
+        assert_eq!(alice.iter().collect::<Vec<u32>>(), vec![3]);
+        let mapped = MmapLog::open_with_block_size(&path, 512).unwrap();
+        assert_eq!(index.lookup_mapped(&mapped, "@alice").unwrap(), alice);
+        assert!(index.lookup(&log, "").unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prefix_index_save_open() {
+        let path = temp_file("prefix-save", "log");
+        let file = temp_file("prefix-save", "index");
+        let mut log = Log::open_with_block_size(&path, 512).unwrap();
+        for i in 0..30 {
+            log.append(&message(&format!("@{}", i))).unwrap();
+        }
+
+        let mut index = PrefixIndex::open(&file, author_path(), 2).unwrap();
+        index.update(&log).unwrap();
+        index.save(&file).unwrap();
+
+        // Header of 4 + 8 + 4 + 4 + 14 + 4 bytes, then a u32 and a u64 per record
+        let saved = std::fs::read(&file).unwrap();
+        assert_eq!(saved.len(), 38 + 30 * (4 + 8));
+        assert_eq!(&saved[38..42], b"@0\0\0");
+        let second = u64::from_le_bytes(saved[166..174].try_into().unwrap());
+        assert_eq!(second, index.offsets()[1]);
+
+        log.append(&message("@7")).unwrap();
+        let mut reopened = PrefixIndex::open(&file, author_path(), 2).unwrap();
+        assert_eq!(reopened, index);
+        assert_eq!(reopened.update(&log).unwrap(), 1);
+        let sevens = reopened.lookup(&log, "@7").unwrap();
+        assert_eq!(sevens.iter().collect::<Vec<u32>>(), vec![7, 30]);
+
+        assert!(PrefixIndex::open(&file, author_path(), 3).is_err());
+        assert!(PrefixIndex::open(&file, type_path(), 2).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// Iterates over the offsets of the elements of an encoded array.
+///
+/// Elements are checked to fit in the array as they are reached. The first bad one is
+/// yielded as an error and ends the iteration.
+#[derive(Clone, Debug)]
+pub struct ArrayIter<'a> {
+    buf: &'a [u8],
+    c: usize,
+    end: usize,
+}
+
+impl<'a> ArrayIter<'a> {
+    /// Fails if the value at `start` is truncated or not an array.
+    pub fn new(buf: &'a [u8], start: usize) -> Result<ArrayIter<'a>> {
+        let (c, end) = container(buf, start, ARRAY)?;
+        Ok(ArrayIter { buf, c, end })
+    }
+}
+
+impl<'a> Iterator for ArrayIter<'a> {
+    type Item = Result<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.c >= self.end {
+            return None;
+        }
+
+        let value_start = self.c;
+        match read_tag_within(self.buf, value_start, self.end) {
+            Ok((_, len, bytes)) => {
+                self.c += bytes + len;
+                Some(Ok(value_start))
+            }
+            Err(e) => {
+                self.c = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterates over the `(key_offset, value_offset)` pairs of an encoded object.
+///
+/// Like `ArrayIter`, a malformed entry is yielded as an error and ends the iteration.
+#[derive(Clone, Debug)]
+pub struct ObjectIter<'a> {
+    buf: &'a [u8],
+    c: usize,
+    end: usize,
+}
+
+impl<'a> ObjectIter<'a> {
+    /// Fails if the value at `start` is truncated or not an object.
+    pub fn new(buf: &'a [u8], start: usize) -> Result<ObjectIter<'a>> {
+        let (c, end) = container(buf, start, OBJECT)?;
+        Ok(ObjectIter { buf, c, end })
+    }
+
+    fn entry(&mut self) -> Result<(usize, usize)> {
+        let key_start = self.c;
+        let (_, key_len, key_bytes) = read_tag_within(self.buf, key_start, self.end)?;
+        let value_start = key_start + key_bytes + key_len;
+        let (_, value_len, value_bytes) = read_tag_within(self.buf, value_start, self.end)?;
+        self.c = value_start + value_bytes + value_len;
+
+        Ok((key_start, value_start))
+    }
+}
+
+impl<'a> Iterator for ObjectIter<'a> {
+    type Item = Result<(usize, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.c >= self.end {
+            return None;
+        }
+
+        let entry = self.entry();
+        if entry.is_err() {
+            self.c = self.end;
+        }
+        Some(entry)
+    }
+}
+
+/// Returns the bounds of the payload of the container at `start`.
+fn container(buf: &[u8], start: usize, expected: usize) -> Result<(usize, usize)> {
+    let (field_type, len, bytes) = read_tag(buf, start)?;
+    if field_type != expected {
+        return Err(BipfError::InvalidTag {
+            offset: start,
+            tag: field_type,
+        });
+    }
+
+    Ok((start + bytes, start + bytes + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::error::*;
+    use crate::iter::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_array_iter() {
+        let buf = json!([1, "two", [3], {"four": 4}]).to_bipf().unwrap();
+        let values: Vec<serde_json::Value> = ArrayIter::new(&buf, 0)
+            .unwrap()
+            .map(|c| decode_rec(&buf, c.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(
+            values,
+            vec![json!(1), json!("two"), json!([3]), json!({"four": 4})]
+        );
+        assert_eq!(
+            ArrayIter::new(&json!([]).to_bipf().unwrap(), 0)
+                .unwrap()
+                .count(),
+            0
+        );
+        assert!(matches!(
+            ArrayIter::new(&json!({}).to_bipf().unwrap(), 0),
+            Err(BipfError::InvalidTag {
+                offset: 0,
+                tag: OBJECT
+            })
+        ));
+    }
+
+    #[test]
+    fn test_object_iter() {
+        let buf = json!({"a": 1, "bc": [true], "d": {"e": null}})
+            .to_bipf()
+            .unwrap();
+        let entries: Vec<(serde_json::Value, serde_json::Value)> = ObjectIter::new(&buf, 0)
+            .unwrap()
+            .map(|entry| {
+                let (k, v) = entry.unwrap();
+                (decode_rec(&buf, k).unwrap(), decode_rec(&buf, v).unwrap())
+            })
+            .collect();
+
+        assert_eq!(
+            entries,
+            vec![
+                (json!("a"), json!(1)),
+                (json!("bc"), json!([true])),
+                (json!("d"), json!({"e": null})),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iter_stops_at_malformed_entry() {
+        // {"a": "bc"} where the value claims 3 bytes and spills out of the object
+        let buf: Vec<u8> = vec![0x2d, 0x08, 0x61, 0x18, 0x62, 0x63, 0x64];
+        let mut entries = ObjectIter::new(&buf, 0).unwrap();
+
+        assert!(matches!(
+            entries.next(),
+            Some(Err(BipfError::LengthOverflow { offset: 3 }))
+        ));
+        assert_eq!(entries.next().is_none(), true);
+    }
+
+    #[test]
+    fn test_iterate_reports_match() {
+        let buf = json!({"a": 1, "b": 2, "c": 3}).to_bipf().unwrap();
+        let mut seen = Vec::new();
+        let found = iterate(&buf, 0, |buf, value, key| {
+            seen.push(decode_rec(buf, key).unwrap());
+            decode_rec(buf, value).unwrap() == json!(2)
+        })
+        .unwrap();
+
+        assert_eq!(found, seek_key(&buf, Some(0), String::from("b")).unwrap());
+        assert_eq!(seen, vec![json!("a"), json!("b")]);
+
+        let buf = json!([10, 20, 30]).to_bipf().unwrap();
+        let mut visited = 0;
+        let found = iterate(&buf, 0, |_, _, i| {
+            visited = i + 1;
+            false
+        })
+        .unwrap();
+        assert_eq!(found, None);
+        assert_eq!(visited, 3);
+
+        // Scalars have nothing to iterate, unless they are cut short
+        let buf = json!("abc").to_bipf().unwrap();
+        assert_eq!(iterate(&buf, 0, |_, _, _| true).unwrap(), None);
+        assert!(iterate(&buf[..2], 0, |_, _, _| true).is_err());
+    }
+}
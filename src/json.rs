@@ -0,0 +1,478 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
+use crate::value::{bytes_to_json, double_to_json, extension_json, JsonOptions};
+use either::*;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
+use serde_json::Value;
+use std::fmt;
+use std::io::{Read, Write};
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// Encodes JSON text without building a `serde_json::Value` first.
+///
+/// Numbers are classified like `Value::to_bipf` does. Unlike `Value`, which keeps the last
+/// of duplicated keys, every key is written out in order.
+pub fn from_json_str(json: &str) -> Result<Vec<u8>> {
+    let mut de = serde_json::Deserializer::from_str(json);
+    let buf = transcode(&mut de).map_err(json_error)?;
+    de.end().map_err(json_error)?;
+    Ok(buf)
+}
+
+/// Like `from_json_str`, reading a single JSON value from `reader`.
+pub fn from_json_reader<R: Read>(reader: R) -> Result<Vec<u8>> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let buf = transcode(&mut de).map_err(json_error)?;
+    de.end().map_err(json_error)?;
+    Ok(buf)
+}
+
+/// Encodes each of the whitespace separated JSON values read from `reader`, such as the
+/// lines of an NDJSON file.
+pub fn from_json_stream<R: Read>(reader: R) -> impl Iterator<Item = Result<Vec<u8>>> {
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<Transcoded>()
+        .map(|r| r.map(|t| t.0).map_err(json_error))
+}
+
+fn transcode<'de, D: Deserializer<'de>>(de: D) -> std::result::Result<Vec<u8>, D::Error> {
+    let mut out = Vec::new();
+    Transcoder { out: &mut out }.deserialize(de)?;
+    Ok(out)
+}
+
+fn json_error(e: serde_json::Error) -> BipfError {
+    BipfError::Message(e.to_string())
+}
+
+/// An encoded value, for `StreamDeserializer`.
+struct Transcoded(Vec<u8>);
+
+impl<'de> Deserialize<'de> for Transcoded {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> std::result::Result<Self, D::Error> {
+        transcode(de).map(Transcoded)
+    }
+}
+
+/// Writes whatever value it is handed to `out`.
+struct Transcoder<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for Transcoder<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, de: D) -> std::result::Result<(), D::Error> {
+        de.deserialize_any(self)
+    }
+}
+
+impl<'a> Transcoder<'a> {
+    fn scalar(self, field_type: usize, payload: &[u8]) {
+        write_tag(self.out, field_type, payload.len());
+        self.out.extend_from_slice(payload);
+    }
+
+    fn integer<E: de::Error>(self, v: i128) -> std::result::Result<(), E> {
+        match encode_integer(v, IntegerPolicy::Double).map_err(E::custom)? {
+            Left(i) => self.scalar(INT, &i.to_le_bytes()),
+            Right(d) => self.scalar(DOUBLE, &d.to_le_bytes()),
+        }
+        Ok(())
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Transcoder<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<(), E> {
+        self.scalar(BOOLNULL, &[]);
+        Ok(())
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> std::result::Result<(), E> {
+        self.scalar(BOOLNULL, &[v as u8]);
+        Ok(())
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<(), E> {
+        self.integer(v as i128)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<(), E> {
+        self.integer(v as i128)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> std::result::Result<(), E> {
+        self.scalar(DOUBLE, &v.to_le_bytes());
+        Ok(())
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<(), E> {
+        self.scalar(STRING, v.as_bytes());
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        let start = begin_container(self.out);
+        while seq
+            .next_element_seed(Transcoder { out: self.out })?
+            .is_some()
+        {}
+        end_container(self.out, start, ARRAY);
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        let start = begin_container(self.out);
+        // JSON keys are strings, so the key goes through visit_str like any other string
+        while map.next_key_seed(Transcoder { out: self.out })?.is_some() {
+            map.next_value_seed(Transcoder { out: self.out })?;
+        }
+        end_container(self.out, start, OBJECT);
+        Ok(())
+    }
+}
+
+/// Layout of the JSON text written by `to_json_writer_with`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JsonStyle {
+    /// No whitespace at all, like `JSON.stringify(value)`.
+    Compact,
+    /// Two spaces of indentation, like `JSON.stringify(value, null, 2)`.
+    Pretty,
+}
+
+/// Writes the value at `start` to `out` as compact JSON, without decoding it first.
+pub fn to_json_writer<W: Write>(buf: &[u8], start: usize, out: W) -> Result<()> {
+    to_json_writer_with(buf, start, out, JsonStyle::Compact, &JsonOptions::default())
+}
+
+/// Like `to_json_writer`, with control over the layout and the lossy conversions.
+pub fn to_json_writer_with<W: Write>(
+    buf: &[u8],
+    start: usize,
+    out: W,
+    style: JsonStyle,
+    options: &JsonOptions,
+) -> Result<()> {
+    let end = buf.len();
+    match style {
+        JsonStyle::Compact => {
+            JsonWriter::new(buf, out, CompactFormatter, options).value(start, end, 0)
+        }
+        JsonStyle::Pretty => {
+            JsonWriter::new(buf, out, PrettyFormatter::new(), options).value(start, end, 0)
+        }
+    }?;
+    Ok(())
+}
+
+pub fn to_json_string(buf: &[u8], start: usize) -> Result<String> {
+    to_json_string_with(buf, start, JsonStyle::Compact, &JsonOptions::default())
+}
+
+pub fn to_json_string_with(
+    buf: &[u8],
+    start: usize,
+    style: JsonStyle,
+    options: &JsonOptions,
+) -> Result<String> {
+    let mut out = Vec::new();
+    to_json_writer_with(buf, start, &mut out, style, options)?;
+    // Only complete, valid UTF-8 strings are ever written
+    String::from_utf8(out).map_err(|e| BipfError::Message(e.to_string()))
+}
+
+struct JsonWriter<'a, W: Write, F: Formatter> {
+    buf: &'a [u8],
+    out: W,
+    fmt: F,
+    options: &'a JsonOptions,
+}
+
+impl<'a, W: Write, F: Formatter> JsonWriter<'a, W, F> {
+    fn new(buf: &'a [u8], out: W, fmt: F, options: &'a JsonOptions) -> Self {
+        JsonWriter {
+            buf,
+            out,
+            fmt,
+            options,
+        }
+    }
+
+    /// Writes the value at `start`, which must end before `end`, and returns its end.
+    fn value(&mut self, start: usize, end: usize, depth: usize) -> Result<usize> {
+        let (field_type, len, bytes) = read_tag_within(self.buf, start, end)?;
+        let value_start = start + bytes;
+        let value_end = value_start + len;
+        let payload = &self.buf[value_start..value_end];
+
+        match field_type {
+            STRING => self.string(value_start, payload)?,
+            BUFFER => self.json(&bytes_to_json(payload, self.options.bytes)?)?,
+            INT => match payload.try_into() {
+                Ok(b) => self.fmt.write_i32(&mut self.out, i32::from_le_bytes(b))?,
+                Err(_) => return Err(BipfError::InvalidLength { offset: start }),
+            },
+            DOUBLE => match payload.try_into() {
+                Ok(b) => self.double(f64::from_le_bytes(b))?,
+                Err(_) => return Err(BipfError::InvalidLength { offset: start }),
+            },
+            BOOLNULL => match payload {
+                [] | [UNDEFINED] => self.fmt.write_null(&mut self.out)?,
+                [b] if *b <= 1 => self.fmt.write_bool(&mut self.out, *b == 1)?,
+                _ => {
+                    return Err(BipfError::InvalidBoolNull {
+                        offset: value_start,
+                    })
+                }
+            },
+            EXTENSION => {
+                let (subtype, data) = split_extension(self.buf, value_start, len)?;
+                self.json(&extension_json(subtype, data, self.options.extensions)?)?
+            }
+            ARRAY | OBJECT if depth >= MAX_DEPTH => {
+                return Err(BipfError::DepthExceeded { offset: start })
+            }
+            ARRAY => {
+                self.fmt.begin_array(&mut self.out)?;
+                let mut c = value_start;
+                while c < value_end {
+                    self.fmt
+                        .begin_array_value(&mut self.out, c == value_start)?;
+                    c = self.value(c, value_end, depth + 1)?;
+                    self.fmt.end_array_value(&mut self.out)?;
+                }
+                self.fmt.end_array(&mut self.out)?
+            }
+            OBJECT => {
+                self.fmt.begin_object(&mut self.out)?;
+                let mut c = value_start;
+                while c < value_end {
+                    let (key_type, key_len, key_bytes) = read_tag_within(self.buf, c, value_end)?;
+                    if key_type != STRING {
+                        return Err(BipfError::InvalidTag {
+                            offset: c,
+                            tag: key_type,
+                        });
+                    }
+                    self.fmt.begin_object_key(&mut self.out, c == value_start)?;
+                    let key_start = c + key_bytes;
+                    self.string(key_start, &self.buf[key_start..key_start + key_len])?;
+                    self.fmt.end_object_key(&mut self.out)?;
+                    c = key_start + key_len;
+                    if c == value_end {
+                        return Err(BipfError::Truncated { offset: c });
+                    }
+
+                    self.fmt.begin_object_value(&mut self.out)?;
+                    c = self.value(c, value_end, depth + 1)?;
+                    self.fmt.end_object_value(&mut self.out)?;
+                }
+                self.fmt.end_object(&mut self.out)?
+            }
+            _ => {
+                return Err(BipfError::InvalidTag {
+                    offset: start,
+                    tag: field_type,
+                })
+            }
+        }
+
+        Ok(value_end)
+    }
+
+    fn string(&mut self, start: usize, payload: &[u8]) -> Result<()> {
+        match std::str::from_utf8(payload) {
+            Ok(s) => serde_json::to_writer(&mut self.out, s).map_err(json_error),
+            Err(e) => Err(BipfError::InvalidUtf8 {
+                offset: start + e.valid_up_to(),
+            }),
+        }
+    }
+
+    fn double(&mut self, d: f64) -> Result<()> {
+        if d.is_finite() {
+            self.fmt.write_f64(&mut self.out, d)?;
+            Ok(())
+        } else {
+            self.json(&double_to_json(d, self.options.non_finite)?)
+        }
+    }
+
+    /// Writes the small trees that buffers, extensions and non-finite doubles map to.
+    fn json(&mut self, value: &Value) -> Result<()> {
+        match value {
+            Value::Null => self.fmt.write_null(&mut self.out)?,
+            Value::Bool(b) => self.fmt.write_bool(&mut self.out, *b)?,
+            Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+                (Some(u), _, _) => self.fmt.write_u64(&mut self.out, u)?,
+                (_, Some(i), _) => self.fmt.write_i64(&mut self.out, i)?,
+                (_, _, Some(d)) => self.fmt.write_f64(&mut self.out, d)?,
+                _ => self.fmt.write_null(&mut self.out)?,
+            },
+            Value::String(s) => serde_json::to_writer(&mut self.out, s).map_err(json_error)?,
+            Value::Array(v) => {
+                self.fmt.begin_array(&mut self.out)?;
+                for (i, x) in v.iter().enumerate() {
+                    self.fmt.begin_array_value(&mut self.out, i == 0)?;
+                    self.json(x)?;
+                    self.fmt.end_array_value(&mut self.out)?;
+                }
+                self.fmt.end_array(&mut self.out)?
+            }
+            Value::Object(o) => {
+                self.fmt.begin_object(&mut self.out)?;
+                for (i, (k, v)) in o.iter().enumerate() {
+                    self.fmt.begin_object_key(&mut self.out, i == 0)?;
+                    serde_json::to_writer(&mut self.out, k).map_err(json_error)?;
+                    self.fmt.end_object_key(&mut self.out)?;
+                    self.fmt.begin_object_value(&mut self.out)?;
+                    self.json(v)?;
+                    self.fmt.end_object_value(&mut self.out)?;
+                }
+                self.fmt.end_object(&mut self.out)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::error::*;
+    use crate::json::*;
+    use crate::value::*;
+    use serde_json::{json, Value};
+
+    const SAMPLE: &str = r#"{
+        "name": "bipf",
+        "ints": [0, -1, 2147483647, -2147483648, 2147483648, -2147483649, 9007199254740993],
+        "doubles": [0.5, -1e300, 1.0, 18446744073709551615],
+        "escapes": "tab\t quote\" snowman☃ pair😀",
+        "nested": {"a": [true, false, null, {}, []], "": "empty key"},
+        "long": "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"
+    }"#;
+
+    #[test]
+    fn test_from_json_str_matches_value() {
+        let value: Value = serde_json::from_str(SAMPLE).unwrap();
+
+        assert_eq!(from_json_str(SAMPLE).unwrap(), value.to_bipf().unwrap());
+        assert_eq!(
+            from_json_reader(SAMPLE.as_bytes()).unwrap(),
+            value.to_bipf().unwrap()
+        );
+        assert_eq!(from_json_str("1").unwrap(), json!(1).to_bipf().unwrap());
+    }
+
+    #[test]
+    fn test_from_json_str_errors() {
+        assert!(from_json_str("").is_err());
+        assert!(from_json_str("[1, 2").is_err());
+        assert!(from_json_str("{\"a\": 1} 2").is_err());
+        assert!(from_json_str("{1: 2}").is_err());
+    }
+
+    #[test]
+    fn test_from_json_stream() {
+        let ndjson = "{\"a\": 1}\n[2, 3]\n\n\"four\"\n";
+        let records: Vec<Vec<u8>> = from_json_stream(ndjson.as_bytes())
+            .map(|r| r.unwrap())
+            .collect();
+
+        assert_eq!(
+            records,
+            vec![
+                json!({"a": 1}).to_bipf().unwrap(),
+                json!([2, 3]).to_bipf().unwrap(),
+                json!("four").to_bipf().unwrap(),
+            ]
+        );
+        let mut broken = from_json_stream("1 [".as_bytes());
+        assert!(broken.next().unwrap().is_ok());
+        assert!(broken.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_to_json_string_matches_serde_json() {
+        let buf = from_json_str(SAMPLE).unwrap();
+        // Integers outside of the i32 range come back as doubles
+        let value = decode(&buf).unwrap();
+
+        assert_eq!(to_json_string(&buf, 0).unwrap(), value.to_string());
+        assert_eq!(
+            to_json_string_with(&buf, 0, JsonStyle::Pretty, &JsonOptions::default()).unwrap(),
+            serde_json::to_string_pretty(&value).unwrap()
+        );
+
+        let nested = seek_key(&buf, Some(0), String::from("nested"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            to_json_string(&buf, nested).unwrap(),
+            value["nested"].to_string()
+        );
+    }
+
+    #[test]
+    fn test_to_json_string_buffers() {
+        let buf = BipfValue::Array(vec![
+            BipfValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef, 0x01]),
+            BipfValue::Double(f64::NAN),
+            BipfValue::Undefined,
+        ])
+        .to_bipf()
+        .unwrap();
+        let with = |bytes| {
+            let options = JsonOptions {
+                bytes,
+                ..JsonOptions::default()
+            };
+            to_json_string_with(&buf, 0, JsonStyle::Compact, &options).unwrap()
+        };
+
+        assert_eq!(
+            to_json_string(&buf, 0).unwrap(),
+            r#"[{"type":"Buffer","data":[222,173,190,239,1]},null,null]"#
+        );
+        assert_eq!(with(BytesMapping::Base64), r#"["3q2+7wE=",null,null]"#);
+        assert_eq!(with(BytesMapping::Hex), r#"["deadbeef01",null,null]"#);
+        assert_eq!(
+            with(BytesMapping::Array),
+            r#"[[222,173,190,239,1],null,null]"#
+        );
+        assert!(to_json_string_with(
+            &buf,
+            0,
+            JsonStyle::Compact,
+            &JsonOptions {
+                bytes: BytesMapping::Error,
+                ..JsonOptions::default()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_to_json_writer_rejects_malformed() {
+        let buf = json!({"a": [1, "two"]}).to_bipf().unwrap();
+        for end in 0..buf.len() {
+            assert!(to_json_string(&buf[..end], 0).is_err());
+        }
+        assert!(matches!(
+            to_json_writer(&[0x18, 0x61, 0xff, 0x62], 0, Vec::new()),
+            Err(BipfError::InvalidUtf8 { offset: 2 })
+        ));
+    }
+}
@@ -1,9 +1,36 @@
 use neon::prelude::*;
-mod bipf;
 mod bindgen_impl;
+mod bipf;
 
 pub use crate::bipf::*;
+mod bitset;
+mod error;
+mod explain;
+mod extension;
+mod index;
+mod iter;
+mod json;
+mod log;
 mod neon_impl;
+mod serde_impl;
+mod validate;
+mod value;
+mod view;
+mod writer;
+
+pub use crate::bitset::*;
+pub use crate::error::*;
+pub use crate::explain::*;
+pub use crate::extension::*;
+pub use crate::index::*;
+pub use crate::iter::*;
+pub use crate::json::*;
+pub use crate::log::*;
+pub use crate::serde_impl::*;
+pub use crate::validate::*;
+pub use crate::value::*;
+pub use crate::view::*;
+pub use crate::writer::*;
 
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
@@ -11,12 +38,18 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("decode", neon_impl::decode)?;
     cx.export_function("encodingLength", neon_impl::encoding_length)?;
     cx.export_function("seekKey", neon_impl::seek_key)?;
+    cx.export_function("seekPath", neon_impl::seek_path)?;
+    cx.export_function("createSeekPath", neon_impl::create_seek_path)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::bipf::*;
+    use crate::error::*;
+    use crate::serde_impl::*;
+    use crate::value::*;
+    use crate::view::*;
     use serde_json::json;
 
     macro_rules! serde {
@@ -54,15 +87,296 @@ mod tests {
         complex: json!([-1, {"foo": true }]),
     }
 
+    #[test]
+    fn test_encoding_length() {
+        let long = "x".repeat(200);
+        for value in [
+            json!(null),
+            json!(-1),
+            json!(0.5),
+            json!(""),
+            json!([]),
+            json!({"a": [1, "two", null, true], "b": {"c": -1}}),
+            json!({ "long": long }),
+            json!([long, [long, {"k": long}]]),
+        ] {
+            assert_eq!(encoding_length(&value), value.to_bipf().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let value = json!({"hello": ["world", 1, 0.5, null]});
+        let expected = value.to_bipf().unwrap();
+        let mut buf = vec![0xffu8; expected.len() + 4];
+
+        assert_eq!(encode_into(&value, &mut buf, 2).unwrap(), expected.len());
+        assert_eq!(&buf[2..2 + expected.len()], &expected[..]);
+        assert_eq!(&buf[..2], &[0xff, 0xff]);
+        assert_eq!(&buf[2 + expected.len()..], &[0xff, 0xff]);
+
+        let typed = BipfValue::from_json(&value, &JsonOptions::default());
+        let mut typed_buf = vec![0u8; expected.len()];
+        assert_eq!(
+            encode_into(&typed, &mut typed_buf, 0).unwrap(),
+            expected.len()
+        );
+        assert_eq!(typed_buf, expected);
+
+        assert!(matches!(
+            encode_into(&value, &mut buf, 5),
+            Err(BipfError::BufferTooSmall {
+                needed: _,
+                available: _
+            })
+        ));
+        assert_eq!(encode_into(&value, &mut buf, 100).is_err(), true);
+    }
+
+    #[test]
+    fn test_encode_nested_containers() {
+        // Sibling and nested containers of every tag size, to check each gets its own length
+        let value = json!({
+            "a": [[], [1, 2], {"b": "x".repeat(20)}],
+            "c": {"d": ["y".repeat(300), {"e": []}], "f": {}},
+            "g": [null, [true, [0.5]]],
+        });
+        let buf = value.to_bipf().unwrap();
+
+        assert_eq!(buf.len(), encoding_length(&value));
+        assert_eq!(decode(&buf).unwrap(), value);
+        assert_eq!(to_vec(&value).unwrap(), buf);
+        let typed = BipfValue::from_json(&value, &JsonOptions::default());
+        assert_eq!(typed.to_bipf().unwrap(), buf);
+    }
+
     #[test]
     fn test_seek_key() {
         let bipf = json!({"hello": "unnecessary", "dependencies": { "rust": "v2.0.1" }})
             .to_bipf()
             .unwrap();
-        let start = seek_key(&bipf, Some(0), String::from("dependencies"));
+        let start = seek_key(&bipf, Some(0), String::from("dependencies")).unwrap();
 
         assert_eq!(start.is_some(), true);
 
         assert_eq!(decode_rec(&bipf, start.unwrap()).unwrap().is_object(), true);
     }
+
+    #[test]
+    fn test_seek_path() {
+        let bipf = json!({"value": {"content": {"type": "post", "mentions": [{"link": "@a"}, {"link": "@b"}]}}})
+            .to_bipf()
+            .unwrap();
+        let path = [
+            PathSegment::from("value"),
+            PathSegment::from("content"),
+            PathSegment::from("mentions"),
+            PathSegment::from(1),
+            PathSegment::from("link"),
+        ];
+        let start = seek_segments(&bipf, 0, &path).unwrap();
+
+        assert_eq!(decode_rec(&bipf, start.unwrap()).unwrap(), json!("@b"));
+
+        let encoded = json!(["value", "content", "mentions", 1, "link"])
+            .to_bipf()
+            .unwrap();
+        assert_eq!(seek_path(&bipf, 0, &encoded, 0).unwrap(), start);
+
+        let missing = json!(["value", "content", "mentions", 2])
+            .to_bipf()
+            .unwrap();
+        assert_eq!(seek_path(&bipf, 0, &missing, 0).unwrap(), None);
+        assert_eq!(seek_segments(&bipf, 0, &[]).unwrap(), Some(0));
+        assert_eq!(
+            seek_segments(&bipf, 0, &[PathSegment::Index(0)]).unwrap(),
+            None
+        );
+        assert_eq!(
+            seek_path(&bipf, 0, &json!([true]).to_bipf().unwrap(), 0).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_seek_path_compiled() {
+        let path = SeekPath::compile(&["value".into(), "content".into(), "type".into()]);
+        let records = [
+            json!({"key": "%a", "value": {"content": {"type": "post", "text": "hi"}}}),
+            json!({"key": "%b", "value": {"content": {"text": "no type", "typeX": 1}}}),
+            json!({"key": "%c", "value": {"author": "@c", "content": {"type": "vote"}}}),
+            json!({"key": "%d", "value": ["content"]}),
+        ];
+        let expected = [Some(json!("post")), None, Some(json!("vote")), None];
+
+        for (record, expected) in records.iter().zip(expected) {
+            let bipf = record.to_bipf().unwrap();
+            let found = path.seek(&bipf, 0).unwrap();
+            let encoded = json!(["value", "content", "type"]).to_bipf().unwrap();
+
+            assert_eq!(found, seek_path(&bipf, 0, &encoded, 0).unwrap());
+            assert_eq!(found.map(|c| decode_rec(&bipf, c).unwrap()), expected);
+        }
+        assert_eq!(
+            path.encode(),
+            json!(["value", "content", "type"]).to_bipf().unwrap()
+        );
+        let indexed = SeekPath::compile(&["a".into(), 2.into()]);
+        assert_eq!(indexed.encode(), json!(["a", 2]).to_bipf().unwrap());
+    }
+
+    #[test]
+    fn test_decode_error_kinds() {
+        assert!(matches!(
+            decode(&[0x07]),
+            Err(BipfError::InvalidLength { offset: 1 })
+        ));
+        assert!(matches!(
+            decode(&[0x18, b'a', b'b', 0xff]),
+            Err(BipfError::InvalidUtf8 { offset: 3 })
+        ));
+        assert!(matches!(
+            decode(&[0x0e, 0x05]),
+            Err(BipfError::InvalidBoolNull { offset: 1 })
+        ));
+        assert!(matches!(
+            decode(&[0x8c]),
+            Err(BipfError::Truncated { offset: 0 })
+        ));
+        assert!(matches!(
+            decode(&[0x0c, 0x8c]),
+            Err(BipfError::Truncated { offset: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_decode_depth_exceeded() {
+        let mut nested = json!(null);
+        for _ in 0..MAX_DEPTH + 1 {
+            nested = json!([nested]);
+        }
+        let buf = nested.to_bipf().unwrap();
+
+        assert!(matches!(
+            decode(&buf),
+            Err(BipfError::DepthExceeded { offset: _ })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_buffers_do_not_panic() {
+        let buf = json!({"a": [1, "two", null, true, 0.5], "b": {"c": -1}})
+            .to_bipf()
+            .unwrap();
+
+        for end in 0..buf.len() {
+            let truncated = buf[..end].to_vec();
+            assert_eq!(decode(&truncated).is_err(), true);
+            assert_eq!(decode_value(&truncated, 0).is_err(), true);
+            assert_eq!(from_slice::<serde_json::Value>(&truncated).is_err(), true);
+            let _ = seek_key(&truncated, Some(0), String::from("b"));
+            let _ = iterate(&truncated, 0, |_, _, _| false);
+            let _ = BipfRef::new(&truncated, 0).map(|v| v.get("b"));
+        }
+    }
+
+    #[test]
+    fn test_child_overflowing_parent() {
+        // [[1]] where the inner array claims 6 bytes but the outer one only holds 5
+        let buf: Vec<u8> = vec![0x2c, 0x34, 0x22, 0x01, 0x00, 0x00, 0x00, 0x00];
+
+        assert!(matches!(
+            decode(&buf),
+            Err(BipfError::LengthOverflow { offset: 1 })
+        ));
+        assert!(matches!(
+            decode_value(&buf, 0),
+            Err(BipfError::LengthOverflow { offset: 1 })
+        ));
+        assert_eq!(from_slice::<serde_json::Value>(&buf).is_err(), true);
+
+        // {"a": "bc"} where the value claims 3 bytes and spills out of the object
+        let buf: Vec<u8> = vec![0x2d, 0x08, 0x61, 0x18, 0x62, 0x63, 0x64];
+        assert!(matches!(
+            seek_key(&buf, Some(0), String::from("z")),
+            Err(BipfError::LengthOverflow { offset: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_integer_boundaries() {
+        let ints: [i64; 6] = [0, -1, 1, i32::MAX as i64, i32::MIN as i64, 12345];
+        let doubles: [i64; 10] = [
+            i32::MAX as i64 + 1,
+            i32::MIN as i64 - 1,
+            1 << 32,
+            -(1 << 32),
+            (1 << 32) - 1,
+            1 << 53,
+            -(1 << 53),
+            (1 << 53) - 1,
+            i64::MAX,
+            i64::MIN,
+        ];
+
+        for i in ints {
+            let buf = json!(i).to_bipf().unwrap();
+            assert_eq!(read_tag(&buf, 0).unwrap().0, INT, "{}", i);
+            assert_eq!(decode_rec(&buf, 0).unwrap(), json!(i));
+            assert_eq!(to_vec(&i).unwrap(), buf);
+        }
+        for i in doubles {
+            let buf = json!(i).to_bipf().unwrap();
+            assert_eq!(read_tag(&buf, 0).unwrap().0, DOUBLE, "{}", i);
+            assert_eq!(decode_rec(&buf, 0).unwrap(), json!(i as f64));
+            assert_eq!(to_vec(&i).unwrap(), buf);
+            assert_eq!(encoding_length(&json!(i)), buf.len());
+        }
+
+        let buf = json!(u64::MAX).to_bipf().unwrap();
+        assert_eq!(read_tag(&buf, 0).unwrap().0, DOUBLE);
+        assert_eq!(decode_rec(&buf, 0).unwrap(), json!(u64::MAX as f64));
+        assert_eq!(to_vec(&u64::MAX).unwrap(), buf);
+    }
+
+    #[test]
+    fn test_integer_exact_policy() {
+        let exact = EncodeOptions {
+            integers: IntegerPolicy::Exact,
+            ..EncodeOptions::default()
+        };
+
+        // Everything up to 2^53 and powers of two survive the trip through a double
+        for i in [i32::MAX as i64 + 1, -(1 << 53), 1 << 53, 1 << 62, i64::MIN] {
+            assert_eq!(
+                encode_with(&json!(i), &exact).unwrap(),
+                json!(i).to_bipf().unwrap()
+            );
+            assert_eq!(to_vec_with(&i, &exact).unwrap(), to_vec(&i).unwrap());
+        }
+        for i in [(1 << 53) + 1, -(1 << 53) - 1, i64::MAX] {
+            assert!(matches!(
+                encode_with(&json!(i), &exact),
+                Err(BipfError::InexactInteger(v)) if v == i as i128
+            ));
+            assert!(to_vec_with(&i, &exact).is_err());
+        }
+        assert!(matches!(
+            to_vec_with(&u64::MAX, &exact),
+            Err(BipfError::InexactInteger(_))
+        ));
+    }
+
+    #[test]
+    fn test_double_as_int() {
+        assert_eq!(double_as_int(0.0), Some(0));
+        assert_eq!(double_as_int(2147483647.0), Some(i32::MAX));
+        assert_eq!(double_as_int(-2147483648.0), Some(i32::MIN));
+        assert_eq!(double_as_int(2147483648.0), None);
+        assert_eq!(double_as_int(-2147483649.0), None);
+        assert_eq!(double_as_int(4294967295.0), None);
+        assert_eq!(double_as_int(0.5), None);
+        assert_eq!(double_as_int(f64::NAN), None);
+        assert_eq!(double_as_int(f64::INFINITY), None);
+    }
 }
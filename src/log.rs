@@ -0,0 +1,585 @@
+use crate::error::BipfError;
+use crate::validate::validate;
+use memmap2::Mmap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, BipfError>;
+/// A record found by a scan, with its offset, `None` if it was deleted.
+type Scanned<R> = (u64, Option<R>);
+
+/// Block size of async-append-only-log files.
+pub const BLOCK_SIZE: usize = 65536;
+/// Size of the little-endian u16 length in front of every record.
+pub const RECORD_HEADER_SIZE: usize = 2;
+
+/// A file of encoded records, compatible with async-append-only-log.
+///
+/// The file is a sequence of fixed-size blocks. Each block holds records prefixed with their
+/// length as a little-endian u16, and is padded with zeros after the last one, so a zero
+/// length marks the end of the block. A record never spans two blocks, and its offset is its
+/// position in the file. Records are validated when they are appended and when they are read.
+///
+/// A record whose bytes are all zeros, its length left in place, has been deleted. It keeps
+/// its offset and its place in the scan, where it is yielded as `None` so the records after it
+/// keep their sequence numbers.
+pub struct Log {
+    file: File,
+    block_size: usize,
+    /// Offset the next record would be written at, in the last block unless it is full.
+    end: u64,
+}
+
+impl Log {
+    /// Opens or creates the log at `path`, with the default block size.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Log> {
+        Log::open_with_block_size(path, BLOCK_SIZE)
+    }
+
+    pub fn open_with_block_size<P: AsRef<Path>>(path: P, block_size: usize) -> Result<Log> {
+        check_block_size(block_size)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        if len % block_size as u64 != 0 {
+            return Err(BipfError::Message(format!(
+                "log size {} is not a multiple of the block size {}",
+                len, block_size
+            )));
+        }
+
+        let mut log = Log {
+            file,
+            block_size,
+            end: 0,
+        };
+        if len > 0 {
+            let block_start = len - block_size as u64;
+            let block = log.read_block(block_start)?;
+            log.end = block_start + end_of_block(&block) as u64;
+        }
+        Ok(log)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Appends one encoded value and returns its offset.
+    ///
+    /// The value must be well-formed and small enough to fit in a block along with its
+    /// length and the end-of-block marker. A top-level empty string, the single byte 0,
+    /// would read back as deleted and is refused.
+    pub fn append(&mut self, record: &[u8]) -> Result<u64> {
+        validate(record)?;
+        if is_deleted(record) {
+            return Err(BipfError::Message(String::from(
+                "an all-zero record reads as deleted",
+            )));
+        }
+        if record.len() + 2 * RECORD_HEADER_SIZE > self.block_size {
+            return Err(BipfError::Message(format!(
+                "record of {} bytes does not fit in a block of {}",
+                record.len(),
+                self.block_size
+            )));
+        }
+
+        let block_size = self.block_size as u64;
+        let file_len = self.file.metadata()?.len();
+        let in_block = self.end % block_size;
+        if file_len == 0 || in_block + (record.len() + 2 * RECORD_HEADER_SIZE) as u64 > block_size {
+            // Start a new block, the rest of the previous one is already zeros
+            self.end = file_len;
+            self.file.set_len(file_len + block_size)?;
+        }
+
+        let offset = self.end;
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_SIZE + record.len());
+        bytes.extend_from_slice(&(record.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(record);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&bytes)?;
+        self.end += bytes.len() as u64;
+
+        Ok(offset)
+    }
+
+    /// Reads the record at `offset`, as returned by `append` or the scan, `None` if it was
+    /// deleted.
+    pub fn get(&self, offset: u64) -> Result<Option<Vec<u8>>> {
+        let block_size = self.block_size as u64;
+        let in_block = (offset % block_size) as usize;
+        if offset >= self.end || in_block + RECORD_HEADER_SIZE > self.block_size {
+            return Err(no_record(offset));
+        }
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let len = u16::from_le_bytes(header) as usize;
+        if len == 0 || in_block + RECORD_HEADER_SIZE + len > self.block_size {
+            return Err(no_record(offset));
+        }
+
+        let mut record = vec![0u8; len];
+        file.read_exact(&mut record)?;
+        match live_record(offset, &record)? {
+            Some(_) => Ok(Some(record)),
+            None => Ok(None),
+        }
+    }
+
+    /// Scans the records in order, yielding `(offset, record)` pairs, with `None` for the
+    /// deleted ones.
+    pub fn iter(&self) -> LogIter<'_> {
+        self.iter_from(0)
+    }
+
+    /// Like `iter`, starting at the record at `offset`.
+    pub fn iter_from(&self, offset: u64) -> LogIter<'_> {
+        LogIter {
+            log: self,
+            block: Vec::new(),
+            block_start: None,
+            offset,
+            failed: false,
+        }
+    }
+
+    /// Flushes the appended records to disk.
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn read_block(&self, block_start: u64) -> Result<Vec<u8>> {
+        let mut block = vec![0u8; self.block_size];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(&mut block)?;
+        Ok(block)
+    }
+}
+
+/// Forward scan over a `Log`, created by `Log::iter`.
+///
+/// Blocks are read whole. A record that fails validation is yielded as an error and ends the
+/// scan.
+pub struct LogIter<'a> {
+    log: &'a Log,
+    block: Vec<u8>,
+    block_start: Option<u64>,
+    offset: u64,
+    failed: bool,
+}
+
+impl<'a> LogIter<'a> {
+    /// Offset the scan goes on from, just past the last record yielded.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn next_record(&mut self) -> Result<Option<Scanned<Vec<u8>>>> {
+        let block_size = self.log.block_size as u64;
+        loop {
+            if self.offset >= self.log.end {
+                return Ok(None);
+            }
+
+            let block_start = self.offset - self.offset % block_size;
+            if self.block_start != Some(block_start) {
+                self.block = self.log.read_block(block_start)?;
+                self.block_start = Some(block_start);
+            }
+
+            let in_block = (self.offset - block_start) as usize;
+            let record = match record_in_block(&self.block, in_block, self.offset)? {
+                Some(record) => record,
+                None => {
+                    // End of the block, records go on in the next one
+                    self.offset = block_start + block_size;
+                    continue;
+                }
+            };
+            let offset = self.offset;
+            self.offset += (RECORD_HEADER_SIZE + record.len()) as u64;
+            let record = live_record(offset, record)?.map(<[u8]>::to_vec);
+            return Ok(Some((offset, record)));
+        }
+    }
+}
+
+impl<'a> Iterator for LogIter<'a> {
+    type Item = Result<(u64, Option<Vec<u8>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_record();
+        self.failed = next.is_err();
+        next.transpose()
+    }
+}
+
+/// A read-only log mapped in memory, for scanning large files without reading them.
+///
+/// Records are handed out as slices of the mapping, ready for `seek_key`, `decode_rec` or a
+/// `BipfRef` without being copied. They are validated like with `Log`.
+pub struct MmapLog {
+    map: Mmap,
+    block_size: usize,
+}
+
+impl MmapLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapLog> {
+        MmapLog::open_with_block_size(path, BLOCK_SIZE)
+    }
+
+    /// Maps the log at `path`. The file must not be truncated while it is mapped, appending
+    /// to it is fine but the new records are only seen by a new mapping.
+    pub fn open_with_block_size<P: AsRef<Path>>(path: P, block_size: usize) -> Result<MmapLog> {
+        check_block_size(block_size)?;
+        let file = File::open(path)?;
+        // Safety: the mapping is read-only and callers are told not to truncate the file
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() % block_size != 0 {
+            return Err(BipfError::Message(format!(
+                "log size {} is not a multiple of the block size {}",
+                map.len(),
+                block_size
+            )));
+        }
+        Ok(MmapLog { map, block_size })
+    }
+
+    /// The whole mapped file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// The record at `offset`, `None` if it was deleted.
+    pub fn get(&self, offset: u64) -> Result<Option<&[u8]>> {
+        let (block, in_block) = self.block_of(offset).ok_or_else(|| no_record(offset))?;
+        match record_in_block(block, in_block, offset)? {
+            Some(record) => live_record(offset, record),
+            None => Err(no_record(offset)),
+        }
+    }
+
+    /// Scans the records in order, yielding `(offset, record)` pairs, with `None` for the
+    /// deleted ones.
+    pub fn iter(&self) -> MmapLogIter<'_> {
+        self.iter_from(0)
+    }
+
+    pub fn iter_from(&self, offset: u64) -> MmapLogIter<'_> {
+        MmapLogIter {
+            log: self,
+            offset,
+            failed: false,
+        }
+    }
+
+    /// The block holding `offset`, and where `offset` is in it.
+    fn block_of(&self, offset: u64) -> Option<(&[u8], usize)> {
+        let offset = usize::try_from(offset).ok()?;
+        let block_start = offset - offset % self.block_size;
+        let block = self
+            .map
+            .get(block_start..block_start.checked_add(self.block_size)?)?;
+        Some((block, offset - block_start))
+    }
+}
+
+/// Forward scan over an `MmapLog`, created by `MmapLog::iter`.
+pub struct MmapLogIter<'a> {
+    log: &'a MmapLog,
+    offset: u64,
+    failed: bool,
+}
+
+impl<'a> MmapLogIter<'a> {
+    /// Offset the scan goes on from, just past the last record yielded.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn next_record(&mut self) -> Result<Option<Scanned<&'a [u8]>>> {
+        loop {
+            let (block, in_block) = match self.log.block_of(self.offset) {
+                Some(b) => b,
+                None => return Ok(None),
+            };
+            let record = match record_in_block(block, in_block, self.offset)? {
+                Some(record) => record,
+                None => {
+                    self.offset += (self.log.block_size - in_block) as u64;
+                    continue;
+                }
+            };
+            let offset = self.offset;
+            self.offset += (RECORD_HEADER_SIZE + record.len()) as u64;
+            return Ok(Some((offset, live_record(offset, record)?)));
+        }
+    }
+}
+
+impl<'a> Iterator for MmapLogIter<'a> {
+    type Item = Result<(u64, Option<&'a [u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_record();
+        self.failed = next.is_err();
+        next.transpose()
+    }
+}
+
+/// A block holds at least one record and its end-of-block marker, and a record length fits
+/// in the u16 header.
+fn check_block_size(block_size: usize) -> Result<()> {
+    if block_size <= 2 * RECORD_HEADER_SIZE || block_size > u16::MAX as usize + 1 {
+        return Err(BipfError::Message(format!(
+            "invalid block size {}",
+            block_size
+        )));
+    }
+    Ok(())
+}
+
+/// Offset of the end-of-block marker, where the next record of the block would go.
+fn end_of_block(block: &[u8]) -> usize {
+    let mut c = 0;
+    while c + RECORD_HEADER_SIZE <= block.len() {
+        let len = u16::from_le_bytes([block[c], block[c + 1]]) as usize;
+        if len == 0 || c + RECORD_HEADER_SIZE + len > block.len() {
+            break;
+        }
+        c += RECORD_HEADER_SIZE + len;
+    }
+    c
+}
+
+/// The record starting at `in_block`, `None` at the end-of-block marker.
+fn record_in_block(block: &[u8], in_block: usize, offset: u64) -> Result<Option<&[u8]>> {
+    let len = match block.get(in_block..in_block + RECORD_HEADER_SIZE) {
+        Some(header) => u16::from_le_bytes([header[0], header[1]]) as usize,
+        None => return Ok(None),
+    };
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let data_start = in_block + RECORD_HEADER_SIZE;
+    match block.get(data_start..data_start + len) {
+        Some(record) => Ok(Some(record)),
+        None => Err(no_record(offset)),
+    }
+}
+
+/// `record` if it is well-formed, `None` if it was deleted.
+fn live_record(offset: u64, record: &[u8]) -> Result<Option<&[u8]>> {
+    if is_deleted(record) {
+        return Ok(None);
+    }
+    match validate(record) {
+        Ok(_) => Ok(Some(record)),
+        Err(e) => Err(BipfError::Message(format!(
+            "corrupt record at offset {}: {}",
+            offset, e
+        ))),
+    }
+}
+
+fn is_deleted(record: &[u8]) -> bool {
+    record.iter().all(|b| *b == 0)
+}
+
+fn no_record(offset: u64) -> BipfError {
+    BipfError::Message(format!("no record at offset {}", offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::log::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bipf-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn record(i: usize) -> Vec<u8> {
+        json!({"seq": i, "text": "x".repeat(i % 50)})
+            .to_bipf()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_log_append_get_scan() {
+        let path = temp_log("append");
+        let mut log = Log::open_with_block_size(&path, 256).unwrap();
+
+        let offsets: Vec<u64> = (0..40).map(|i| log.append(&record(i)).unwrap()).collect();
+        assert_eq!(offsets[0], 0);
+        for (i, offset) in offsets.iter().enumerate() {
+            // Records never cross a block boundary
+            let len = (record(i).len() + RECORD_HEADER_SIZE) as u64;
+            assert_eq!(offset / 256, (offset + len - 1) / 256);
+            assert_eq!(log.get(*offset).unwrap(), Some(record(i)));
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len() % 256, 0);
+
+        let scanned: Vec<(u64, Option<Vec<u8>>)> = log.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(scanned.len(), 40);
+        for (i, (offset, r)) in scanned.iter().enumerate() {
+            assert_eq!(*offset, offsets[i]);
+            assert_eq!(*r, Some(record(i)));
+        }
+        assert_eq!(log.iter_from(offsets[30]).count(), 10);
+
+        // Reopening finds the end of the last block
+        drop(log);
+        let mut log = Log::open_with_block_size(&path, 256).unwrap();
+        let next = log.append(&record(40)).unwrap();
+        assert_eq!(
+            log.iter().last().unwrap().unwrap(),
+            (next, Some(record(40)))
+        );
+        assert!(next > offsets[39]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_rejects_bad_records() {
+        let path = temp_log("reject");
+        let mut log = Log::open_with_block_size(&path, 64).unwrap();
+
+        assert!(log.append(&[0x2c, 0x34]).is_err());
+        assert!(log.append(&json!("").to_bipf().unwrap()).is_err());
+        assert!(log
+            .append(&json!("x".repeat(60)).to_bipf().unwrap())
+            .is_err());
+        let offset = log.append(&json!(1).to_bipf().unwrap()).unwrap();
+        assert!(log.get(offset + 1).is_err());
+        assert!(log.get(offset + 64).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_detects_corruption() {
+        let path = temp_log("corrupt");
+        let mut log = Log::open_with_block_size(&path, 64).unwrap();
+        let first = log.append(&json!([1, 2]).to_bipf().unwrap()).unwrap();
+        log.append(&json!("ok").to_bipf().unwrap()).unwrap();
+        drop(log);
+
+        // Make the array claim more bytes than the record holds
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[first as usize + RECORD_HEADER_SIZE] = 0x64;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let log = Log::open_with_block_size(&path, 64).unwrap();
+        assert!(log.get(first).is_err());
+        let mut scan = log.iter();
+        assert!(scan.next().unwrap().is_err());
+        assert!(scan.next().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_log_borrows_records() {
+        let path = temp_log("mmap");
+        let mut log = Log::open_with_block_size(&path, 256).unwrap();
+        let offsets: Vec<u64> = (0..40).map(|i| log.append(&record(i)).unwrap()).collect();
+        log.sync().unwrap();
+
+        let mapped = MmapLog::open_with_block_size(&path, 256).unwrap();
+        let scanned: Vec<(u64, &[u8])> = mapped
+            .iter()
+            .map(|r| r.unwrap())
+            .map(|(offset, r)| (offset, r.unwrap()))
+            .collect();
+        assert_eq!(scanned.len(), 40);
+        for (i, (offset, r)) in scanned.iter().enumerate() {
+            assert_eq!(*offset, offsets[i]);
+            assert_eq!(*r, &record(i)[..]);
+            assert_eq!(mapped.get(*offset).unwrap(), Some(*r));
+        }
+
+        // Records point into the mapping itself
+        let (offset, r) = scanned[7];
+        let start = offset as usize + RECORD_HEADER_SIZE;
+        assert_eq!(r.as_ptr(), mapped.as_bytes()[start..].as_ptr());
+        let seq = seek_key(r, Some(0), String::from("seq")).unwrap().unwrap();
+        assert_eq!(decode_rec(r, seq).unwrap(), json!(7));
+
+        assert_eq!(mapped.iter_from(offsets[35]).count(), 5);
+        assert!(mapped.get(offsets[3] + 1).is_err());
+        assert!(mapped.get(1 << 40).is_err());
+        assert!(mapped.get(u64::MAX).is_err());
+        assert!(mapped.iter_from(u64::MAX).next().is_none());
+        assert!(MmapLog::open_with_block_size(&path, 4).is_err());
+        assert!(MmapLog::open_with_block_size(&path, 1 << 17).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_skips_deleted_records() {
+        let path = temp_log("deleted");
+        let mut log = Log::open_with_block_size(&path, 256).unwrap();
+        let offsets: Vec<u64> = (0..10).map(|i| log.append(&record(i)).unwrap()).collect();
+        log.sync().unwrap();
+
+        // Delete the record in the middle by zeroing its bytes, its length stays
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offsets[5] + RECORD_HEADER_SIZE as u64))
+            .unwrap();
+        file.write_all(&vec![0; record(5).len()]).unwrap();
+        drop(file);
+
+        let log = Log::open_with_block_size(&path, 256).unwrap();
+        assert_eq!(log.get(offsets[5]).unwrap(), None);
+        assert_eq!(log.get(offsets[6]).unwrap(), Some(record(6)));
+        let scanned: Vec<(u64, Option<Vec<u8>>)> = log.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(scanned.len(), 10);
+        for (i, (offset, r)) in scanned.iter().enumerate() {
+            assert_eq!(*offset, offsets[i]);
+            assert_eq!(*r, Some(record(i)).filter(|_| i != 5));
+        }
+
+        let mapped = MmapLog::open_with_block_size(&path, 256).unwrap();
+        assert_eq!(mapped.get(offsets[5]).unwrap(), None);
+        let mut scan = mapped.iter_from(offsets[4]);
+        assert_eq!(
+            scan.next().unwrap().unwrap(),
+            (offsets[4], Some(&record(4)[..]))
+        );
+        assert_eq!(scan.next().unwrap().unwrap(), (offsets[5], None));
+        assert_eq!(scan.offset(), offsets[6]);
+        assert_eq!(
+            scan.next().unwrap().unwrap(),
+            (offsets[6], Some(&record(6)[..]))
+        );
+        assert_eq!(scan.count(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
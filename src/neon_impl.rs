@@ -1,10 +1,11 @@
 use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
+use either::*;
 use integer_encoding::VarInt;
 use neon::prelude::*;
 use std::io::*;
 
-const MAX_I32_F64: f64 = MAX_I32 as f64;
-
 pub fn encoding_length<'a>(mut cx: FunctionContext<'a>) -> JsResult<JsNumber> {
     let arg = cx.argument::<JsValue>(0)?;
     match encoding_length_rec(&mut cx, arg) {
@@ -19,7 +20,7 @@ pub fn encoding_length_rec<'a>(
 ) -> Result<usize> {
     let len = if input.is_a::<JsNull, _>(cx) {
         Ok(JSON_NULL_SIZE)
-    } else if input.is_a::<JsBoolean, _>(cx) {
+    } else if input.is_a::<JsBoolean, _>(cx) || input.is_a::<JsUndefined, _>(cx) {
         Ok(JSON_BOOL_SIZE)
     } else if input.is_a::<JsString, _>(cx) {
         let res = match input.downcast::<JsString, _>(cx) {
@@ -31,11 +32,9 @@ pub fn encoding_length_rec<'a>(
     } else if input.is_a::<JsNumber, _>(cx) {
         match input.downcast::<JsNumber, _>(cx) {
             Ok(x) => Ok({
-                let v = x.value(cx);
-                if v.abs() < MAX_I32_F64 && v.fract() == 0.0 {
-                    JSON_INT_SIZE
-                } else {
-                    JSON_DOUBLE_SIZE
+                match double_as_int(x.value(cx)) {
+                    Some(_) => JSON_INT_SIZE,
+                    None => JSON_DOUBLE_SIZE,
                 }
             }),
             Err(_) => Err(Error::new(ErrorKind::Other, "")),
@@ -88,29 +87,228 @@ pub fn encoding_length_rec<'a>(
     Ok(len + (len << TAG_SIZE).required_space())
 }
 
-pub fn encode<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsBuffer> {
+/// `encode(value)` returns a new buffer, `encode(value, buffer, start)` writes into `buffer`
+/// at `start` and returns the number of bytes written, like the JS library.
+///
+/// The value is walked once, by `flatten`, which also measures it. Every container's length
+/// is then known before it is written, so each tag goes straight to its place in the buffer.
+pub fn encode<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsValue> {
     let val = cx.argument::<JsValue>(0)?;
-    match JType::new(val, &mut cx) {
-        Ok(val) => match val.encode(&mut cx) {
-            Ok(res) => {
-                // Todo write continuously
-                let mut buf = unsafe { JsBuffer::uninitialized(&mut cx, res.len() as u32) }?;
-                let mut out = cx.borrow_mut(&mut buf, |x| x.as_mut_slice::<u8>());
-                out.write(&res[..]);
-
-                Ok(buf)
+    let target = match cx.argument_opt(1) {
+        Some(b) if !b.is_a::<JsUndefined, _>(&mut cx) => {
+            Some(b.downcast_or_throw::<JsBuffer, _>(&mut cx)?)
+        }
+        _ => None,
+    };
+    let start = match cx.argument_opt(2) {
+        Some(i) if !i.is_a::<JsUndefined, _>(&mut cx) => {
+            i.downcast_or_throw::<JsNumber, _>(&mut cx)?.value(&mut cx) as usize
+        }
+        _ => 0,
+    };
+
+    let mut items = Vec::new();
+    let needed = match flatten(&mut cx, val, &mut items) {
+        Ok(needed) => needed,
+        Err(e) => return cx.throw_error(e.to_string()),
+    };
+    let mut buf = match target {
+        None => unsafe { JsBuffer::uninitialized(&mut cx, needed as u32) }?,
+        Some(buf) => {
+            let available = cx.borrow(&buf, |x| x.len()).saturating_sub(start);
+            if needed > available {
+                let e = BipfError::BufferTooSmall { needed, available };
+                return cx.throw_range_error(e.to_string());
             }
-            Err(_) => NeonResult::Err(neon::result::Throw),
-        },
-        Err(_) => NeonResult::Err(neon::result::Throw),
+            buf
+        }
+    };
+
+    let ptr = cx.borrow_mut(&mut buf, |x| x.as_mut_slice::<u8>().as_mut_ptr());
+    // Safety: `start + needed` was checked against the buffer length above, and the buffer
+    // stays alive and in place as long as its handle does, for the whole call
+    let mut out = Target {
+        ptr: unsafe { ptr.add(start) },
+        len: needed,
+        pos: 0,
+    };
+    let written = items
+        .iter()
+        .try_for_each(|item| item.write(&mut cx, &mut out));
+    if let Err(e) = written {
+        return cx.throw_error(e.to_string());
+    }
+
+    match target {
+        None => Ok(buf.upcast()),
+        Some(_) => Ok(cx.number(needed as f64).upcast()),
+    }
+}
+
+/// A JS value taken apart by `flatten`, in the order its encoding is written.
+enum Item<'a> {
+    /// The tag of an array or object, whose members follow.
+    Container(usize, usize),
+    Null,
+    Undefined,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Buffer(Handle<'a, JsBuffer>),
+}
+
+impl<'a> Item<'a> {
+    fn write(&self, cx: &mut FunctionContext<'a>, out: &mut Target) -> Result<()> {
+        match self {
+            Item::Container(field_type, len) => out.write_tag(*field_type, *len),
+            Item::Null => out.write_tag(BOOLNULL, JSON_NULL_SIZE),
+            Item::Undefined => {
+                out.write_tag(BOOLNULL, JSON_BOOL_SIZE)?;
+                out.write(&[UNDEFINED])
+            }
+            Item::Bool(b) => {
+                out.write_tag(BOOLNULL, JSON_BOOL_SIZE)?;
+                out.write(&[*b as u8])
+            }
+            Item::Number(v) => match double_as_int(*v) {
+                Some(i) => {
+                    out.write_tag(INT, JSON_INT_SIZE)?;
+                    out.write(&i.to_le_bytes())
+                }
+                None => {
+                    out.write_tag(DOUBLE, JSON_DOUBLE_SIZE)?;
+                    out.write(&v.to_le_bytes())
+                }
+            },
+            Item::Str(s) => {
+                out.write_tag(STRING, s.len())?;
+                out.write(s.as_bytes())
+            }
+            Item::Buffer(b) => cx.borrow(b, |x| {
+                let data = x.as_slice::<u8>();
+                out.write_tag(BUFFER, data.len())?;
+                out.write(data)
+            }),
+        }
+    }
+}
+
+/// Pushes the items of `input` to `items` and returns the size of its encoding.
+///
+/// This is the only pass over the JS value: strings are copied out and keys and members
+/// looked up once, and each container's length is filled in once its members are measured.
+fn flatten<'a>(
+    cx: &mut FunctionContext<'a>,
+    input: Handle<'a, JsValue>,
+    items: &mut Vec<Item<'a>>,
+) -> Result<usize> {
+    let len = if input.is_a::<JsNull, _>(cx) {
+        items.push(Item::Null);
+        JSON_NULL_SIZE
+    } else if input.is_a::<JsUndefined, _>(cx) {
+        items.push(Item::Undefined);
+        JSON_BOOL_SIZE
+    } else if let Ok(b) = input.downcast::<JsBoolean, _>(cx) {
+        items.push(Item::Bool(b.value(cx)));
+        JSON_BOOL_SIZE
+    } else if let Ok(s) = input.downcast::<JsString, _>(cx) {
+        let s = s.value(cx);
+        let len = s.len();
+        items.push(Item::Str(s));
+        len
+    } else if let Ok(x) = input.downcast::<JsNumber, _>(cx) {
+        let v = x.value(cx);
+        items.push(Item::Number(v));
+        match double_as_int(v) {
+            Some(_) => JSON_INT_SIZE,
+            None => JSON_DOUBLE_SIZE,
+        }
+    } else if let Ok(b) = input.downcast::<JsBuffer, _>(cx) {
+        let len = cx.borrow(&b, |x| x.len());
+        items.push(Item::Buffer(b));
+        len
+    } else if let Ok(arr) = input.downcast::<JsArray, _>(cx) {
+        let members = arr
+            .to_vec(cx)
+            .map_err(|_| Error::other("cannot read array members"))?;
+        let i = items.len();
+        items.push(Item::Container(ARRAY, 0));
+        let mut len = 0;
+        for x in members {
+            len += flatten(cx, x, items)?;
+        }
+        items[i] = Item::Container(ARRAY, len);
+        len
+    } else if let Ok(obj) = input.downcast::<JsObject, _>(cx) {
+        let keys = obj
+            .get_own_property_names(cx)
+            .and_then(|k| k.to_vec(cx))
+            .map_err(|_| Error::other("cannot read object keys"))?;
+        let i = items.len();
+        items.push(Item::Container(OBJECT, 0));
+        let mut len = 0;
+        for jskey in keys {
+            let key = jskey
+                .downcast::<JsString, _>(cx)
+                .map_err(|_| Error::other("object key is not a string"))?
+                .value(cx);
+            len += tagged_length(key.len());
+            items.push(Item::Str(key));
+            let value = obj
+                .get(cx, jskey)
+                .map_err(|_| Error::other("cannot read object value"))?;
+            len += flatten(cx, value, items)?;
+        }
+        items[i] = Item::Container(OBJECT, len);
+        len
+    } else {
+        return Err(Error::other("Unknown type"));
+    };
+
+    Ok(tagged_length(len))
+}
+
+/// The bytes of a JS buffer a value is encoded into.
+///
+/// They are written through a raw pointer because writing JS buffers into it needs the
+/// context, which a borrow of the target would hold for its whole duration. Every write is
+/// checked against `len`.
+struct Target {
+    ptr: *mut u8,
+    len: usize,
+    pos: usize,
+}
+
+impl Target {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        if bytes.len() > self.len - self.pos {
+            return Err(Error::other("encoded value is larger than measured"));
+        }
+        let (src, dst) = (bytes.as_ptr() as usize, self.ptr as usize);
+        if src < dst + self.len && dst < src + bytes.len() {
+            return Err(Error::other("cannot encode a buffer into itself"));
+        }
+
+        // Safety: in bounds as checked above, and `bytes` does not overlap the target
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(self.pos), bytes.len());
+        }
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    fn write_tag(&mut self, field_type: usize, len: usize) -> Result<()> {
+        let mut varint = [0u8; 10];
+        let bytes = (len << TAG_SIZE | field_type).encode_var(&mut varint);
+        self.write(&varint[..bytes])
     }
 }
 
 pub fn seek_key<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsNumber> {
     let buf = cx.argument::<JsBuffer>(0)?;
     let bytes = cx.borrow(&buf, |x| x.as_slice());
-    let start = cx.argument::<JsNumber>(1)?.value(&mut cx) as isize;
-    let start = if start == -1 {
+    let start = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    let start = if start < 0.0 {
         None
     } else {
         Some(start as usize)
@@ -129,265 +327,120 @@ pub fn seek_key<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsNumber> {
         return cx.throw_error("expected 3rd argument to `seek_key` to be a string or buffer");
     };
 
-    Ok(cx.number(match seek_key_internal(bytes, start, &target) {
-        None => -1 as f64,
-        Some(v) => v as f64,
-    }))
+    let found = match start {
+        None => Ok(None),
+        Some(start) => seek_key_bytes(bytes, start, target),
+    };
+    match found {
+        Ok(None) => Ok(cx.number(-1)),
+        Ok(Some(v)) => Ok(cx.number(v as f64)),
+        Err(e) => cx.throw_error(e.to_string()),
+    }
 }
 
-fn seek_key_internal<'a>(bytes: &[u8], start: Option<usize>, target: &[u8]) -> Option<usize> {
-    match start {
-        None => None,
-        Some(start) => {
-            let decoded: (usize, usize) = VarInt::decode_var(&bytes[start..])?;
-            let tag = decoded.0;
-            let ty = tag & TAG_MASK;
-
-            if ty != OBJECT {
-                None
-            } else {
-                let mut c = decoded.1;
-                let len = tag >> TAG_SIZE;
-                let target_length = target.len();
-                let target_buf = target;
-                while c < len {
-                    let key_tag: (usize, usize) = VarInt::decode_var(&bytes[start + c..])?;
-                    c += key_tag.1;
-                    let key_len = key_tag.0 >> TAG_SIZE;
-                    let key_type = key_tag.0 & TAG_MASK;
-
-                    if key_type == STRING && target_length == key_len {
-                        if target_buf == &bytes[start + c..start + c + target_length] {
-                            let next_start = start + c + key_len;
-                            return Some(next_start);
-                        }
-                    }
-
-                    c += key_len;
-                    let value_tag: (usize, usize) = VarInt::decode_var(&bytes[start + c..])?;
-                    c += value_tag.1;
-                    let value_len = value_tag.0 >> TAG_SIZE;
-                    c += value_len;
-                }
+/// `seekPath(buf, start, path, pathStart)`, where `path` is either an encoded array like in the
+/// JS library or a plain array of strings and integers.
+pub fn seek_path<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsNumber> {
+    let buf = cx.argument::<JsBuffer>(0)?;
+    let start = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    let path = cx.argument::<JsValue>(2)?;
+
+    let found = if start < 0.0 {
+        Ok(None)
+    } else if path.is_a::<JsBuffer, _>(&mut cx) {
+        let path = path.downcast_or_throw::<JsBuffer, _>(&mut cx)?;
+        let path_start = match cx.argument_opt(3) {
+            Some(v) => v.downcast_or_throw::<JsNumber, _>(&mut cx)?.value(&mut cx) as usize,
+            None => 0,
+        };
+        let target = cx.borrow(&path, |x| x.as_slice::<u8>());
+        let bytes = cx.borrow(&buf, |x| x.as_slice::<u8>());
+        crate::bipf::seek_path(bytes, start as usize, target, path_start)
+    } else if path.is_a::<JsArray, _>(&mut cx) {
+        let keys = path_keys(&mut cx, path)?;
+        let segments = path_segments(&keys);
+        let bytes = cx.borrow(&buf, |x| x.as_slice::<u8>());
+        seek_segments(bytes, start as usize, &segments)
+    } else {
+        return cx.throw_error("expected 3rd argument to `seekPath` to be a buffer or an array");
+    };
 
-                None
-            }
-        }
+    match found {
+        Ok(None) => Ok(cx.number(-1)),
+        Ok(Some(v)) => Ok(cx.number(v as f64)),
+        Err(e) => cx.throw_error(e.to_string()),
     }
 }
 
-enum JType<'a> {
-    String {
-        v: Handle<'a, JsString>,
-        l: usize,
-    },
-    Buffer {
-        v: Vec<u8>,
-    },
-    Int {
-        v: i32,
-    },
-    Double {
-        v: f64,
-    },
-    Array {
-        v: Vec<JType<'a>>,
-        l: usize,
-    },
-    Object {
-        v: Vec<(Handle<'a, JsString>, JType<'a>)>,
-        l: usize,
-    },
-    BoolNull {
-        v: Option<bool>,
-        l: usize,
-    },
+impl Finalize for SeekPath {}
+
+/// `createSeekPath(path)` compiles `path` once and returns `(buf, start) => pointer`.
+pub fn create_seek_path<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsFunction> {
+    let path = cx.argument::<JsValue>(0)?;
+    let keys = path_keys(&mut cx, path)?;
+    let compiled = cx.boxed(SeekPath::compile(&path_segments(&keys)));
+
+    // Like the JS library, hand out the seek function with its path bound as first argument.
+    let seek = JsFunction::new(&mut cx, seek_compiled_path)?;
+    let bind = seek
+        .get(&mut cx, "bind")?
+        .downcast_or_throw::<JsFunction, _>(&mut cx)?;
+    let null = cx.null();
+    bind.call(
+        &mut cx,
+        seek,
+        vec![null.upcast::<JsValue>(), compiled.upcast()],
+    )?
+    .downcast_or_throw::<JsFunction, _>(&mut cx)
 }
 
-impl<'a> JType<'a> {
-    pub fn new(input: Handle<'a, JsValue>, cx: &mut FunctionContext<'a>) -> Result<JType<'a>> {
-        if input.is_a::<JsNull, _>(cx) {
-            Ok(JType::BoolNull {
-                v: None,
-                l: JSON_NULL_SIZE,
-            })
-        } else if input.is_a::<JsBoolean, _>(cx) {
-            let res = match input.downcast::<JsBoolean, _>(cx) {
-                Ok(b) => Ok(b.value(cx)),
-                _ => Err(Error::new(ErrorKind::Other, "")),
-            }?;
-            Ok(JType::BoolNull {
-                v: Some(res),
-                l: JSON_BOOL_SIZE,
-            })
-        } else if input.is_a::<JsString, _>(cx) {
-            let res = match input.downcast::<JsString, _>(cx) {
-                Ok(b) => Ok(b),
-                _ => Err(Error::new(ErrorKind::Other, "")),
-            }?;
-
-            Ok(JType::String {
-                v: res,
-                l: res.size(cx) as usize,
-            })
-        } else if input.is_a::<JsNumber, _>(cx) {
-            Ok(match input.downcast::<JsNumber, _>(cx) {
-                Ok(x) => Ok({
-                    let v = x.value(cx);
-                    // TODO properly handle numbers
-                    // https://medium.com/angular-in-depth/javascripts-number-type-8d59199db1b6#.9whwe88tz
-                    // https://stackoverflow.com/questions/48500261/check-if-a-float-can-be-converted-to-integer-without-loss/48500414
-                    // Also cf https://github.com/ssbc/bipf/issues/2
-                    if v.abs() < MAX_I32_F64 && v.fract() == 0.0 {
-                        JType::Int { v: v as i32 }
-                    } else {
-                        JType::Double { v }
-                    }
-                }),
-                Err(_) => Err(Error::new(ErrorKind::Other, "")),
-            }?)
-        } else if input.is_a::<JsBuffer, _>(cx) {
-            match input.downcast::<JsBuffer, _>(cx) {
-                Ok(b) => Ok(JType::Buffer {
-                    v: cx.borrow(&b, |x| x.as_slice().to_vec()),
-                }),
-                Err(_) => Err(Error::new(ErrorKind::Other, "")),
-            }
-        } else if input.is_a::<JsArray, _>(cx) {
-            let v: Vec<JType> = match input.downcast::<JsArray, _>(cx) {
-                Ok(res) => match res.to_vec(cx) {
-                    Ok(vec) => vec.iter().map(|x| JType::new(*x, cx)).collect(),
-                    Err(_) => Err(Error::new(ErrorKind::Other, "")),
-                },
-                Err(_) => Err(Error::new(ErrorKind::Other, "")),
-            }?;
-
-            let mut l = 0;
-            for x in &v {
-                let base_len = JType::length(&x);
-                l += base_len + (base_len << TAG_SIZE).required_space()
-            }
-
-            Ok(JType::Array { v, l })
-        } else if input.is_a::<JsObject, _>(cx) {
-            let res = match input.downcast::<JsObject, _>(cx) {
-                Ok(b) => Ok(b),
-                _ => Err(Error::new(ErrorKind::Other, "")),
-            }?;
-
-            let v: Vec<(Handle<'a, JsString>, JType<'a>)> =
-                match res.get_own_property_names(cx).unwrap().to_vec(cx) {
-                    Ok(vec) => vec
-                        .iter()
-                        .map(|x| match x.downcast::<JsString, _>(cx) {
-                            Ok(s) => {
-                                let inner = JType::new(
-                                    match res.get(cx, s) {
-                                        Ok(s) => Ok(s),
-                                        _ => Err(Error::new(ErrorKind::Other, "")),
-                                    }?,
-                                    cx,
-                                )?;
-
-                                Ok((s, inner))
-                            }
-                            _ => Err(Error::new(ErrorKind::Other, "")),
-                        })
-                        .collect(),
-                    Err(_) => Err(Error::new(ErrorKind::Other, "")),
-                }?;
-
-            let mut l = 0;
-            for (k, v) in &v {
-                let key_len = k.size(cx) as usize;
-                let val_length = JType::length(&v);
-                l += key_len
-                    + (key_len << TAG_SIZE).required_space()
-                    + val_length
-                    + (val_length << TAG_SIZE).required_space()
-            }
-
-            Ok(JType::Object { v, l })
-        } else {
-            Err(Error::new(ErrorKind::Other, "Unknown type"))
-        }
-    }
-
-    pub fn encode_rec(
-        &self,
-        cx: &mut FunctionContext<'a>,
-        buf: &mut Vec<u8>,
-        start: usize,
-    ) -> Result<usize> {
-        let length_varint = self.length() << TAG_SIZE | self.get_type();
-        let varint = length_varint.encode_var_vec();
-        let varint_length = varint.len();
-        buf.write(&varint)?;
-
-        Ok(varint_length
-            + (match self {
-                JType::Buffer { v } => buf.write(v),
-                JType::String { v, l: _ } => buf.write(v.value(cx).as_bytes()),
-                JType::Int { v } => buf.write(&v.to_le_bytes()),
-                JType::Double { v } => buf.write(&v.to_le_bytes()),
-                JType::BoolNull { v, l: _ } => match v {
-                    None => Ok(JSON_NULL_SIZE),
-                    Some(b) => buf.write(&[if *b { 1 } else { 0 }]),
-                },
-                JType::Array { v, l: _ } => {
-                    let mut p = start;
-                    for i in v {
-                        p += i.encode_rec(cx, buf, p)?
-                    }
-                    Ok(p - start)
-                }
-                JType::Object { v, l: _ } => {
-                    let mut p = start;
-                    for (k, u) in v {
-                        p += JType::String {
-                            v: *k,
-                            l: k.size(cx) as usize,
-                        }
-                        .encode_rec(cx, buf, p)?;
-                        p += u.encode_rec(cx, buf, p)?;
-                    }
-                    Ok(p - start)
-                }
-            })?)
+fn seek_compiled_path<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsNumber> {
+    let compiled = cx.argument::<JsBox<SeekPath>>(0)?;
+    let buf = cx.argument::<JsBuffer>(1)?;
+    let start = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    if start < 0.0 {
+        return Ok(cx.number(-1));
     }
 
-    pub fn encode(&self, cx: &mut FunctionContext<'a>) -> Result<Vec<u8>> {
-        let mut buf: Vec<u8> = Vec::with_capacity(self.length());
-        self.encode_rec(cx, &mut buf, 0)?;
-
-        buf.flush()?;
-
-        Ok(buf)
+    let bytes = cx.borrow(&buf, |x| x.as_slice::<u8>());
+    match compiled.seek(bytes, start as usize) {
+        Ok(None) => Ok(cx.number(-1)),
+        Ok(Some(v)) => Ok(cx.number(v as f64)),
+        Err(e) => cx.throw_error(e.to_string()),
     }
+}
 
-    pub fn length(&self) -> usize {
-        match self {
-            JType::Buffer { v } => v.len(),
-            JType::String { v: _, l } => *l,
-            JType::Int { v: _ } => JSON_INT_SIZE,
-            JType::Double { v: _ } => JSON_DOUBLE_SIZE,
-            JType::Array { v: _, l } => *l,
-            JType::Object { v: _, l } => *l,
-            JType::BoolNull { v: _, l } => *l,
+/// Reads a JS path array into owned keys and indices.
+fn path_keys<'a>(
+    cx: &mut FunctionContext<'a>,
+    path: Handle<'a, JsValue>,
+) -> NeonResult<Vec<Either<String, usize>>> {
+    let path = path.downcast_or_throw::<JsArray, _>(cx)?.to_vec(cx)?;
+    let mut keys = Vec::with_capacity(path.len());
+    for segment in path {
+        if segment.is_a::<JsString, _>(cx) {
+            let key = segment.downcast_or_throw::<JsString, _>(cx)?.value(cx);
+            keys.push(Left(key));
+        } else if segment.is_a::<JsNumber, _>(cx) {
+            let index = segment.downcast_or_throw::<JsNumber, _>(cx)?.value(cx);
+            if index < 0.0 || index.fract() != 0.0 {
+                return cx.throw_error("path indices must be non-negative integers");
+            }
+            keys.push(Right(index as usize));
+        } else {
+            return cx.throw_error("path must be an array of strings and integers");
         }
     }
+    Ok(keys)
+}
 
-    pub fn get_type(&self) -> usize {
-        match self {
-            JType::Buffer { v: _ } => BUFFER,
-            JType::String { v: _, l: _ } => STRING,
-            JType::Int { v: _ } => INT,
-            JType::Double { v: _ } => DOUBLE,
-            JType::Array { v: _, l: _ } => ARRAY,
-            JType::Object { v: _, l: _ } => OBJECT,
-            JType::BoolNull { v: _, l: _ } => BOOLNULL,
-        }
-    }
+fn path_segments(keys: &[Either<String, usize>]) -> Vec<PathSegment<'_>> {
+    keys.iter()
+        .map(|k| match k {
+            Left(key) => PathSegment::Key(key),
+            Right(index) => PathSegment::Index(*index),
+        })
+        .collect()
 }
 
 pub fn decode<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsValue> {
@@ -397,14 +450,14 @@ pub fn decode<'a>(mut cx: FunctionContext<'a>) -> JsResult<'a, JsValue> {
     let start = match cx.argument_opt(1) {
         Some(i) => match i.downcast::<JsNumber, _>(&mut cx) {
             Ok(i) => Ok(i.value(&mut cx) as usize),
-            Err(_) => NeonResult::Err(neon::result::Throw),
+            Err(_) => cx.throw_type_error("expected 2nd argument to `decode` to be a number"),
         },
         None => Ok(0),
     }?;
 
     match decode_rec_neon(&mut cx, buf, start) {
         Ok(a) => Ok(a),
-        Err(_) => NeonResult::Err(neon::result::Throw),
+        Err(e) => cx.throw_error(e.to_string()),
     }
 }
 
@@ -413,16 +466,9 @@ pub fn decode_rec_neon<'a>(
     buf: &[u8],
     start: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start..]);
-    let (tag, bytes) = match decoded {
-        Some(v) => Ok(v),
-        None => Err(Error::from(ErrorKind::InvalidInput)),
-    }?;
+    let (field_type, len, bytes) = read_tag(buf, start)?;
 
-    let field_type = tag & TAG_MASK;
-    let len = tag >> TAG_SIZE;
-
-    decode_type_neon(cx, field_type, buf, start + bytes, len)
+    decode_type_neon(cx, field_type, buf, start + bytes, len, 0)
 }
 
 pub fn decode_type_neon<'a>(
@@ -431,15 +477,17 @@ pub fn decode_type_neon<'a>(
     buf: &[u8],
     start: usize,
     len: usize,
+    depth: usize,
 ) -> Result<Handle<'a, JsValue>> {
     match field_type {
         STRING => decode_string_neon(cx, buf, start, len),
         BOOLNULL => decode_boolnull_neon(cx, buf, start, len),
-        INT => decode_integer_neon(cx, buf, start),
-        DOUBLE => decode_double_neon(cx, buf, start),
-        ARRAY => decode_array_neon(cx, buf, start, len),
-        OBJECT => decode_object_neon(cx, buf, start, len),
+        INT => decode_integer_neon(cx, buf, start, len),
+        DOUBLE => decode_double_neon(cx, buf, start, len),
+        ARRAY => decode_array_neon(cx, buf, start, len, depth),
+        OBJECT => decode_object_neon(cx, buf, start, len, depth),
         BUFFER => decode_buffer_neon(cx, buf, start, len),
+        EXTENSION => decode_extension_neon(cx, buf, start, len),
         _ => Err(Error::new(ErrorKind::Other, "invalid type")),
     }
 }
@@ -450,22 +498,11 @@ pub fn decode_boolnull_neon<'a>(
     start: usize,
     len: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    if len == 0 {
-        Ok(cx.null().upcast())
-    } else {
-        let s = buf[start];
-        if s > 2 {
-            Err(Error::new(ErrorKind::Other, "Invalid boolnull"))
-        } else {
-            if len > 1 {
-                Err(Error::new(
-                    ErrorKind::Other,
-                    "Invalid boolnull, len must be > 1",
-                ))
-            } else {
-                Ok(cx.boolean(if s == 1 { true } else { false }).upcast())
-            }
-        }
+    match payload(buf, start, len)? {
+        [] => Ok(cx.null().upcast()),
+        [UNDEFINED] => Ok(cx.undefined().upcast()),
+        [s] if *s <= 1 => Ok(cx.boolean(*s == 1).upcast()),
+        _ => Err(BipfError::InvalidBoolNull { offset: start }.into()),
     }
 }
 
@@ -475,13 +512,13 @@ pub fn decode_string_neon<'a>(
     start: usize,
     len: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    let raw_str = std::str::from_utf8(&buf[start..start + len]);
+    let raw_str = std::str::from_utf8(payload(buf, start, len)?);
     match raw_str {
         std::result::Result::Ok(v) => Ok(cx.string(v).upcast()),
-        std::result::Result::Err(_) => Err(Error::new(
-            ErrorKind::Other,
-            "Could not decode utf-8 string",
-        )),
+        std::result::Result::Err(e) => Err(BipfError::InvalidUtf8 {
+            offset: start + e.valid_up_to(),
+        }
+        .into()),
     }
 }
 
@@ -491,24 +528,49 @@ pub fn decode_buffer_neon<'a>(
     start: usize,
     len: usize,
 ) -> Result<Handle<'a, JsValue>> {
+    let data = payload(buf, start, len)?;
     let mut res = match cx.buffer(len as u32) {
         Ok(b) => Ok(b),
         Err(_) => Err(Error::from(ErrorKind::InvalidInput)),
     }?;
 
     let mut out = cx.borrow_mut(&mut res, |x| x.as_mut_slice::<u8>());
-    out.write(&buf[start..start + len])?;
+    out.write_all(data)?;
     Ok(res.upcast())
 }
 
+/// Extensions have no JS type of their own, they decode to `{type: 'Extension', subtype, data}`
+/// with the data as a Buffer, whatever the subtype.
+pub fn decode_extension_neon<'a>(
+    cx: &mut FunctionContext<'a>,
+    buf: &[u8],
+    start: usize,
+    len: usize,
+) -> Result<Handle<'a, JsValue>> {
+    let (subtype, data) = split_extension(buf, start, len)?;
+    let obj = cx.empty_object();
+    let type_name = cx.string("Extension").upcast::<JsValue>();
+    let subtype = cx.number(subtype as f64).upcast::<JsValue>();
+    let data = decode_buffer_neon(cx, data, 0, data.len())?;
+
+    for (key, value) in [("type", type_name), ("subtype", subtype), ("data", data)] {
+        if obj.set(cx, key, value).is_err() {
+            return Err(Error::other("could not set extension property"));
+        }
+    }
+    Ok(obj.upcast())
+}
+
 pub fn decode_integer_neon<'a>(
     cx: &mut FunctionContext<'a>,
     buf: &[u8],
     start: usize,
+    len: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    let bytes: [u8; 4] = buf[start..start + 4]
-        .try_into()
-        .expect("slice with incorrect length");
+    let bytes: [u8; 4] = match payload(buf, start, len)?.try_into() {
+        Ok(b) => b,
+        Err(_) => return Err(BipfError::InvalidLength { offset: start }.into()),
+    };
     Ok(cx.number(i32::from_le_bytes(bytes)).upcast())
 }
 
@@ -516,10 +578,12 @@ pub fn decode_double_neon<'a>(
     cx: &mut FunctionContext<'a>,
     buf: &[u8],
     start: usize,
+    len: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    let bytes: [u8; 8] = buf[start..start + 8]
-        .try_into()
-        .expect("slice with incorrect length");
+    let bytes: [u8; 8] = match payload(buf, start, len)?.try_into() {
+        Ok(b) => b,
+        Err(_) => return Err(BipfError::InvalidLength { offset: start }.into()),
+    };
     Ok(cx.number(f64::from_le_bytes(bytes)).upcast())
 }
 
@@ -528,23 +592,21 @@ pub fn decode_array_neon<'a>(
     buf: &[u8],
     start: usize,
     len: usize,
+    depth: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    let mut c = 0;
-    let mut vec: Vec<Handle<'a, JsValue>> = Vec::new();
+    if depth >= MAX_DEPTH {
+        return Err(BipfError::DepthExceeded { offset: start }.into());
+    }
 
-    while c < len {
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            Some(v) => Result::Ok(v),
-            None => Result::Err(Error::from(ErrorKind::InvalidInput)),
-        }?;
+    let end = start + len;
+    let mut c = start;
+    let mut vec: Vec<Handle<'a, JsValue>> = Vec::new();
 
+    while c < end {
+        let (field_type, len, bytes) = read_tag_within(buf, c, end)?;
         c += bytes;
 
-        let field_type = tag & TAG_MASK;
-        let len = tag >> TAG_SIZE;
-
-        vec.push(decode_type_neon(cx, field_type, buf, start + c, len)?);
+        vec.push(decode_type_neon(cx, field_type, buf, c, len, depth + 1)?);
 
         c += len;
     }
@@ -563,32 +625,25 @@ pub fn decode_object_neon<'a>(
     buf: &[u8],
     start: usize,
     len: usize,
+    depth: usize,
 ) -> Result<Handle<'a, JsValue>> {
-    let mut c = 0;
+    if depth >= MAX_DEPTH {
+        return Err(BipfError::DepthExceeded { offset: start }.into());
+    }
+
+    let end = start + len;
+    let mut c = start;
     let obj = cx.empty_object();
 
-    while c < len {
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            Some(v) => Result::Ok(v),
-            None => Result::Err(Error::from(ErrorKind::InvalidInput)),
-        }?;
+    while c < end {
+        let (_, len, bytes) = read_tag_within(buf, c, end)?;
         c += bytes;
-        let len = tag >> TAG_SIZE;
-        let key = decode_string_neon(cx, buf, start + c, len)?;
+        let key = decode_string_neon(cx, buf, c, len)?;
         c += len;
 
-        let decoded: Option<(usize, usize)> = VarInt::decode_var(&buf[start + c..]);
-        let (tag, bytes) = match decoded {
-            Some(v) => Result::Ok(v),
-            None => Err(Error::from(ErrorKind::InvalidInput)),
-        }?;
-
-        let field_type = tag & TAG_MASK;
-        let len = tag >> TAG_SIZE;
-
+        let (field_type, len, bytes) = read_tag_within(buf, c, end)?;
         c += bytes;
-        let value = decode_type_neon(cx, field_type, buf, start + c, len)?;
+        let value = decode_type_neon(cx, field_type, buf, c, len, depth + 1)?;
         c += len;
         obj.set(cx, key, value);
     }
@@ -0,0 +1,1153 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
+use crate::value::{encode_double, NonFiniteMapping};
+use either::*;
+use serde::de::value::SeqDeserializer;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use serde::Deserialize;
+use std::fmt::Display;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// Serializes `value` straight to BIPF, without going through `serde_json::Value`.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    to_vec_with(value, &EncodeOptions::default())
+}
+
+/// Like `to_vec`, with control over how numbers are encoded.
+pub fn to_vec_with<T: Serialize + ?Sized>(value: &T, options: &EncodeOptions) -> Result<Vec<u8>> {
+    let mut serializer = Serializer::new(options);
+    value.serialize(&mut serializer)?;
+    Ok(serializer.into_inner())
+}
+
+/// Deserializes a `T` from a buffer holding exactly one encoded value.
+///
+/// Strings and buffers can be borrowed from `buf` as `&'de str` and `&'de [u8]`.
+pub fn from_slice<'de, T: Deserialize<'de>>(buf: &'de [u8]) -> Result<T> {
+    let mut deserializer = Deserializer::new(buf);
+    let value = T::deserialize(&mut deserializer)?;
+    if deserializer.pos == buf.len() {
+        Ok(value)
+    } else {
+        Err(BipfError::TrailingBytes {
+            offset: deserializer.pos,
+        })
+    }
+}
+
+/// A serde serializer writing BIPF to a buffer, for `to_vec_with` or serializing several
+/// values one after the other.
+pub struct Serializer {
+    output: Vec<u8>,
+    integers: IntegerPolicy,
+    non_finite: NonFiniteMapping,
+}
+
+impl Serializer {
+    pub fn new(options: &EncodeOptions) -> Serializer {
+        Serializer {
+            output: Vec::new(),
+            integers: options.integers,
+            non_finite: options.non_finite,
+        }
+    }
+
+    /// The values serialized so far.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.output
+    }
+
+    fn write_int(&mut self, v: i32) {
+        write_tag(&mut self.output, INT, JSON_INT_SIZE);
+        self.output.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn write_double(&mut self, v: f64) {
+        write_tag(&mut self.output, DOUBLE, JSON_DOUBLE_SIZE);
+        self.output.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn write_float(&mut self, v: f64) -> Result<()> {
+        match encode_double(v, self.non_finite)? {
+            Some(v) => self.write_double(v),
+            None => write_tag(&mut self.output, BOOLNULL, JSON_NULL_SIZE),
+        }
+        Ok(())
+    }
+
+    // Integers outside of the i32 range become doubles, like JS numbers do.
+    fn write_integer(&mut self, v: i128) -> Result<()> {
+        match encode_integer(v, self.integers)? {
+            Left(i) => self.write_int(i),
+            Right(d) => self.write_double(d),
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, v: &str) {
+        write_tag(&mut self.output, STRING, v.len());
+        self.output.extend_from_slice(v.as_bytes());
+    }
+}
+
+/// A container being serialized; `inner` is only used by enum variants, which
+/// are wrapped in a single-key object like in serde_json.
+pub struct Compound<'a> {
+    ser: &'a mut Serializer,
+    start: usize,
+    inner: Option<(usize, usize)>,
+}
+
+impl<'a> Compound<'a> {
+    fn end(self, field_type: usize) -> Result<()> {
+        if let Some((inner_start, inner_type)) = self.inner {
+            end_container(&mut self.ser.output, inner_start, inner_type);
+        }
+        end_container(&mut self.ser.output, self.start, field_type);
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = BipfError;
+
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        write_tag(&mut self.output, BOOLNULL, JSON_BOOL_SIZE);
+        self.output.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.write_int(v as i32);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.write_int(v as i32);
+        Ok(())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.write_int(v);
+        Ok(())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.write_integer(v as i128)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.write_integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.write_int(v as i32);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.write_int(v as i32);
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.write_integer(v as i128)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.write_integer(v as i128)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.write_float(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.write_float(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.write_str(v.encode_utf8(&mut [0u8; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        write_tag(&mut self.output, BUFFER, v.len());
+        self.output.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        write_tag(&mut self.output, BOOLNULL, JSON_NULL_SIZE);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.write_str(variant);
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        let start = begin_container(&mut self.output);
+        self.write_str(variant);
+        value.serialize(&mut *self)?;
+        end_container(&mut self.output, start, OBJECT);
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        let start = begin_container(&mut self.output);
+        Ok(Compound {
+            ser: self,
+            start,
+            inner: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        let start = begin_container(&mut self.output);
+        self.write_str(variant);
+        let inner_start = begin_container(&mut self.output);
+        Ok(Compound {
+            ser: self,
+            start,
+            inner: Some((inner_start, ARRAY)),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        let start = begin_container(&mut self.output);
+        Ok(Compound {
+            ser: self,
+            start,
+            inner: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        let start = begin_container(&mut self.output);
+        self.write_str(variant);
+        let inner_start = begin_container(&mut self.output);
+        Ok(Compound {
+            ser: self,
+            start,
+            inner: Some((inner_start, OBJECT)),
+        })
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, ARRAY)
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, ARRAY)
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, ARRAY)
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, OBJECT)
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        key.serialize(MapKeySerializer {
+            ser: &mut *self.ser,
+        })
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, OBJECT)
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.ser.write_str(key);
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, OBJECT)
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.ser.write_str(key);
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<()> {
+        Compound::end(self, OBJECT)
+    }
+}
+
+/// Object keys are always strings on the wire; integer and char keys are
+/// stringified the way serde_json does it.
+struct MapKeySerializer<'a> {
+    ser: &'a mut Serializer,
+}
+
+impl<'a> MapKeySerializer<'a> {
+    fn write_display<T: Display>(self, v: T) -> Result<()> {
+        self.ser.write_str(&v.to_string());
+        Ok(())
+    }
+}
+
+fn key_must_be_a_string() -> BipfError {
+    BipfError::Message(String::from("key must be a string"))
+}
+
+impl<'a> ser::Serializer for MapKeySerializer<'a> {
+    type Ok = ();
+    type Error = BipfError;
+
+    type SerializeSeq = ser::Impossible<(), BipfError>;
+    type SerializeTuple = ser::Impossible<(), BipfError>;
+    type SerializeTupleStruct = ser::Impossible<(), BipfError>;
+    type SerializeTupleVariant = ser::Impossible<(), BipfError>;
+    type SerializeMap = ser::Impossible<(), BipfError>;
+    type SerializeStruct = ser::Impossible<(), BipfError>;
+    type SerializeStructVariant = ser::Impossible<(), BipfError>;
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.ser.write_str(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.write_display(v)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(key_must_be_a_string())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(key_must_be_a_string())
+    }
+}
+
+pub struct Deserializer<'de> {
+    buf: &'de [u8],
+    pos: usize,
+    // End of the innermost container, children may not extend past it
+    end: usize,
+    // Containers entered and not yet left, capped at MAX_DEPTH
+    depth: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(buf: &'de [u8]) -> Deserializer<'de> {
+        Deserializer {
+            buf,
+            pos: 0,
+            end: buf.len(),
+            depth: 0,
+        }
+    }
+
+    /// Reads the tag at the current position, returning `(field_type, len, payload_start)`.
+    fn peek_tag(&self) -> Result<(usize, usize, usize)> {
+        let (field_type, len, bytes) = read_tag_within(self.buf, self.pos, self.end)?;
+        Ok((field_type, len, self.pos + bytes))
+    }
+
+    /// Consumes a scalar value, returning its type, payload offset and payload.
+    fn next_scalar(&mut self) -> Result<(usize, usize, &'de [u8])> {
+        let (field_type, len, start) = self.peek_tag()?;
+        self.pos = start + len;
+        Ok((field_type, start, &self.buf[start..start + len]))
+    }
+
+    fn next_str(&mut self) -> Result<&'de str> {
+        let (_, start, payload) = self.next_scalar()?;
+        std::str::from_utf8(payload).map_err(|e| BipfError::InvalidUtf8 {
+            offset: start + e.valid_up_to(),
+        })
+    }
+
+    /// Descends into a container, runs `f` over its children, and checks they used it up exactly.
+    fn container<T>(
+        &mut self,
+        start: usize,
+        len: usize,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(BipfError::DepthExceeded { offset: self.pos });
+        }
+
+        let parent_end = self.end;
+        self.pos = start;
+        self.end = start + len;
+        self.depth += 1;
+        let value = f(self)?;
+        if self.pos != self.end {
+            return Err(BipfError::TrailingBytes { offset: self.pos });
+        }
+        self.depth -= 1;
+        self.end = parent_end;
+        Ok(value)
+    }
+
+    fn deserialize_integer<V: Visitor<'de>>(&mut self, visitor: V) -> Result<V::Value> {
+        let (field_type, _, _) = self.peek_tag()?;
+        if field_type != DOUBLE {
+            return de::Deserializer::deserialize_any(self, visitor);
+        }
+
+        // Large integers are written as doubles, so accept integral doubles here
+        let tag_start = self.pos;
+        let (_, _, payload) = self.next_scalar()?;
+        let v = f64::from_le_bytes(
+            payload
+                .try_into()
+                .map_err(|_| BipfError::InvalidLength { offset: tag_start })?,
+        );
+        // Out of range doubles would saturate, let the visitor reject them as doubles
+        if v.fract() != 0.0 || !v.is_finite() {
+            visitor.visit_f64(v)
+        } else if v < 0.0 && v >= i64::MIN as f64 {
+            visitor.visit_i64(v as i64)
+        } else if v >= 0.0 && v < u64::MAX as f64 {
+            visitor.visit_u64(v as u64)
+        } else {
+            visitor.visit_f64(v)
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = BipfError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let (field_type, len, start) = self.peek_tag()?;
+        match field_type {
+            ARRAY => self.container(start, len, |de| visitor.visit_seq(Access { de })),
+            OBJECT => self.container(start, len, |de| visitor.visit_map(Access { de })),
+            STRING => visitor.visit_borrowed_str(self.next_str()?),
+            _ => {
+                let tag_start = self.pos;
+                let invalid_length = |_| BipfError::InvalidLength { offset: tag_start };
+                let (_, start, payload) = self.next_scalar()?;
+                match (field_type, payload) {
+                    (BUFFER, _) => visitor.visit_borrowed_bytes(payload),
+                    (INT, _) => visitor.visit_i32(i32::from_le_bytes(
+                        payload.try_into().map_err(invalid_length)?,
+                    )),
+                    (DOUBLE, _) => visitor.visit_f64(f64::from_le_bytes(
+                        payload.try_into().map_err(invalid_length)?,
+                    )),
+                    (BOOLNULL, []) | (BOOLNULL, [UNDEFINED]) => visitor.visit_unit(),
+                    (BOOLNULL, [0]) => visitor.visit_bool(false),
+                    (BOOLNULL, [1]) => visitor.visit_bool(true),
+                    (BOOLNULL, _) => Err(BipfError::InvalidBoolNull { offset: start }),
+                    (EXTENSION, _) => {
+                        let (subtype, data) = split_extension(self.buf, start, payload.len())?;
+                        visitor.visit_map(ExtensionAccess {
+                            subtype,
+                            data,
+                            field: 0,
+                        })
+                    }
+                    _ => Err(BipfError::InvalidTag {
+                        offset: tag_start,
+                        tag: field_type,
+                    }),
+                }
+            }
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_integer(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.peek_tag()? {
+            (BUFFER, _, _) | (STRING, _, _) => visitor.visit_borrowed_bytes(self.next_scalar()?.2),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.peek_tag()? {
+            (BOOLNULL, JSON_NULL_SIZE, _) => {
+                self.next_scalar()?;
+                visitor.visit_none()
+            }
+            (BOOLNULL, JSON_BOOL_SIZE, start) if self.buf[start] == UNDEFINED => {
+                self.next_scalar()?;
+                visitor.visit_none()
+            }
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.peek_tag()? {
+            (STRING, _, _) => visitor.visit_enum(self.next_str()?.into_deserializer()),
+            (OBJECT, len, start) => {
+                self.container(start, len, |de| visitor.visit_enum(Access { de }))
+            }
+            (field_type, _, _) => Err(BipfError::InvalidTag {
+                offset: self.pos,
+                tag: field_type,
+            }),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let (_, len, start) = self.peek_tag()?;
+        self.pos = start + len;
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string unit unit_struct seq tuple
+        tuple_struct map struct identifier
+    }
+}
+
+/// Walks the children of an array or object, or the single key of an enum variant.
+struct Access<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Access<'a, 'de> {
+    type Error = BipfError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.de.pos >= self.de.end {
+            Ok(None)
+        } else {
+            seed.deserialize(&mut *self.de).map(Some)
+        }
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Access<'a, 'de> {
+    type Error = BipfError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.de.pos >= self.de.end {
+            Ok(None)
+        } else {
+            seed.deserialize(&mut *self.de).map(Some)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for Access<'a, 'de> {
+    type Error = BipfError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for Access<'a, 'de> {
+    type Error = BipfError;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+/// An extension seen as the map `{"type": "Extension", "subtype": n, "data": [...]}`, the
+/// same shape `decode` gives it in JSON.
+struct ExtensionAccess<'de> {
+    subtype: u64,
+    data: &'de [u8],
+    /// Index in `EXTENSION_FIELDS` of the next key.
+    field: usize,
+}
+
+const EXTENSION_FIELDS: [&str; 3] = ["type", "subtype", "data"];
+
+impl<'de> de::MapAccess<'de> for ExtensionAccess<'de> {
+    type Error = BipfError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match EXTENSION_FIELDS.get(self.field) {
+            Some(key) => {
+                self.field += 1;
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.field {
+            1 => seed.deserialize("Extension".into_deserializer()),
+            2 => seed.deserialize(self.subtype.into_deserializer()),
+            _ => seed.deserialize(SeqDeserializer::new(self.data.iter().copied())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::error::*;
+    use crate::serde_impl::*;
+    use crate::value::*;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Content {
+        Post { text: String },
+        Vote(i32, bool),
+        Contact(String),
+        About,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        sequence: u32,
+        timestamp: i64,
+        author: String,
+        previous: Option<String>,
+        content: Vec<Content>,
+        #[serde(with = "serde_bytes_compat")]
+        signature: Vec<u8>,
+    }
+
+    // Vec<u8> serializes as a sequence by default, this routes it through serialize_bytes
+    mod serde_bytes_compat {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(v)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+            <&[u8]>::deserialize(d).map(|b| b.to_vec())
+        }
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Borrowed<'a> {
+        author: &'a str,
+        #[serde(borrow)]
+        signature: &'a [u8],
+    }
+
+    fn message() -> Message {
+        Message {
+            sequence: 2,
+            timestamp: 1633093416347,
+            author: String::from("@jerive"),
+            previous: None,
+            content: vec![
+                Content::Post {
+                    text: String::from("hello"),
+                },
+                Content::Vote(-1, true),
+                Content::Contact(String::from("@dominic")),
+                Content::About,
+            ],
+            signature: vec![0xde, 0xad, 0xbe, 0xef],
+        }
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let buf = to_vec(&message()).unwrap();
+
+        assert_eq!(from_slice::<Message>(&buf).unwrap(), message());
+    }
+
+    #[test]
+    fn test_serde_matches_value_encoding() {
+        let input = json!({"a": [1, "two", null, true, 0.5], "b": {"c": -1}});
+
+        assert_eq!(to_vec(&input).unwrap(), input.to_bipf().unwrap());
+        assert_eq!(
+            from_slice::<serde_json::Value>(&input.to_bipf().unwrap()).unwrap(),
+            input
+        );
+    }
+
+    #[test]
+    fn test_serde_serializer() {
+        let mut serializer = Serializer::new(&EncodeOptions::default());
+        message().serialize(&mut serializer).unwrap();
+        "next".serialize(&mut serializer).unwrap();
+
+        let mut expected = to_vec(&message()).unwrap();
+        expected.extend_from_slice(&to_vec("next").unwrap());
+        assert_eq!(serializer.into_inner(), expected);
+    }
+
+    #[test]
+    fn test_serde_extension() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Extension {
+            #[serde(rename = "type")]
+            kind: String,
+            subtype: u64,
+            data: Vec<u8>,
+        }
+
+        let value = BipfValue::Array(vec![
+            BipfValue::Extension(300, vec![1, 2]),
+            BipfValue::Int(3),
+        ]);
+        let buf = value.to_bipf().unwrap();
+
+        let as_json = from_slice::<serde_json::Value>(&buf).unwrap();
+        assert_eq!(as_json, decode(&buf).unwrap());
+        assert_eq!(
+            as_json,
+            json!([{"type": "Extension", "subtype": 300, "data": [1, 2]}, 3])
+        );
+        let (ext, three): (Extension, i32) = from_slice(&buf).unwrap();
+        assert_eq!(
+            ext,
+            Extension {
+                kind: String::from("Extension"),
+                subtype: 300,
+                data: vec![1, 2],
+            }
+        );
+        assert_eq!(three, 3);
+
+        let tagged = JsonOptions {
+            extensions: ExtensionMapping::TaggedObject,
+            ..JsonOptions::default()
+        };
+        let back = BipfValue::from_json(&as_json, &tagged);
+        assert_eq!(back.to_bipf().unwrap(), buf);
+    }
+
+    #[test]
+    fn test_serde_borrowed() {
+        let buf = to_vec(&message()).unwrap();
+        let borrowed: Borrowed = from_slice(&buf).unwrap();
+
+        assert_eq!(borrowed.author, "@jerive");
+        assert_eq!(borrowed.signature, &[0xde, 0xad, 0xbe, 0xef]);
+        let author_start = borrowed.author.as_ptr() as usize - buf.as_ptr() as usize;
+        assert_eq!(author_start < buf.len(), true);
+    }
+
+    #[test]
+    fn test_serde_errors() {
+        let buf = to_vec(&message()).unwrap();
+
+        assert_eq!(from_slice::<Message>(&buf[..buf.len() - 1]).is_err(), true);
+        assert_eq!(from_slice::<String>(&to_vec(&1).unwrap()).is_err(), true);
+        assert_eq!(
+            to_vec(&std::collections::HashMap::from([(true, 1)])).is_err(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_serde_non_finite_doubles() {
+        let values = vec![f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
+        let buf = to_vec(&values).unwrap();
+        let back: Vec<f64> = from_slice(&buf).unwrap();
+
+        assert!(back[0].is_nan());
+        assert_eq!(back[1..], values[1..]);
+
+        let null = EncodeOptions {
+            non_finite: NonFiniteMapping::Null,
+            ..EncodeOptions::default()
+        };
+        let error = EncodeOptions {
+            non_finite: NonFiniteMapping::Error,
+            ..EncodeOptions::default()
+        };
+        assert_eq!(
+            to_vec_with(&values, &null).unwrap(),
+            json!([null, null, null]).to_bipf().unwrap()
+        );
+        assert!(to_vec_with(&f32::NAN, &error).is_err());
+        assert_eq!(
+            to_vec_with(&0.5, &error).unwrap(),
+            json!(0.5).to_bipf().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_serde_depth_exceeded() {
+        let nested = |depth: usize| {
+            let mut value = json!(null);
+            for _ in 0..depth {
+                value = json!([value]);
+            }
+            value.to_bipf().unwrap()
+        };
+
+        assert!(from_slice::<serde_json::Value>(&nested(MAX_DEPTH)).is_ok());
+        assert!(matches!(
+            from_slice::<serde_json::Value>(&nested(MAX_DEPTH + 1)),
+            Err(BipfError::DepthExceeded { offset: _ })
+        ));
+    }
+
+    #[test]
+    fn test_serde_integral_doubles_out_of_range() {
+        let big = json!(1e30).to_bipf().unwrap();
+        let small = json!(-1e30).to_bipf().unwrap();
+
+        assert!(from_slice::<u64>(&big).is_err());
+        assert!(from_slice::<i64>(&small).is_err());
+        assert_eq!(from_slice::<f64>(&big).unwrap(), 1e30);
+        assert_eq!(
+            from_slice::<i64>(&json!(-4294967296i64).to_bipf().unwrap()).unwrap(),
+            -4294967296
+        );
+        assert_eq!(
+            from_slice::<u64>(&json!(4294967296u64).to_bipf().unwrap()).unwrap(),
+            4294967296
+        );
+    }
+
+    #[test]
+    fn test_serde_undefined() {
+        let undefined = vec![0x0e, UNDEFINED];
+
+        assert_eq!(from_slice::<Option<bool>>(&undefined).unwrap(), None);
+        assert_eq!(from_slice::<()>(&undefined).unwrap(), ());
+        assert!(from_slice::<bool>(&undefined).is_err());
+    }
+}
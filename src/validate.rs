@@ -0,0 +1,178 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// What `validate` found while walking a well-formed buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    /// Number of values, object keys included.
+    pub values: usize,
+    /// Deepest container nesting, 0 for a scalar.
+    pub depth: usize,
+}
+
+/// Checks that `buf` holds exactly one well-formed value, without decoding it.
+///
+/// Every tag must be known, every child must fit inside its parent, object keys must be
+/// strings, strings must be valid UTF-8 and boolnulls one of the legal payloads. Extensions
+/// only need a subtype, their data is not checked.
+pub fn validate(buf: &[u8]) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+    let end = validate_at(buf, 0, buf.len(), 0, &mut report)?;
+
+    if end != buf.len() {
+        return Err(BipfError::TrailingBytes { offset: end });
+    }
+    Ok(report)
+}
+
+/// Validates the value at `start` and returns the offset right after it.
+fn validate_at(
+    buf: &[u8],
+    start: usize,
+    end: usize,
+    depth: usize,
+    report: &mut ValidationReport,
+) -> Result<usize> {
+    let (field_type, len, bytes) = read_tag_within(buf, start, end)?;
+    let value_start = start + bytes;
+    let value_end = value_start + len;
+    let payload = &buf[value_start..value_end];
+    report.values += 1;
+
+    match field_type {
+        STRING => {
+            if let Err(e) = std::str::from_utf8(payload) {
+                return Err(BipfError::InvalidUtf8 {
+                    offset: value_start + e.valid_up_to(),
+                });
+            }
+        }
+        BUFFER => {}
+        EXTENSION => {
+            split_extension(buf, value_start, len)?;
+        }
+        INT if len == JSON_INT_SIZE => {}
+        DOUBLE if len == JSON_DOUBLE_SIZE => {}
+        INT | DOUBLE => return Err(BipfError::InvalidLength { offset: start }),
+        BOOLNULL => match payload {
+            [] => {}
+            [b] if *b <= 2 => {}
+            _ => {
+                return Err(BipfError::InvalidBoolNull {
+                    offset: value_start,
+                })
+            }
+        },
+        ARRAY | OBJECT if depth >= MAX_DEPTH => {
+            return Err(BipfError::DepthExceeded { offset: start })
+        }
+        ARRAY => {
+            report.depth = report.depth.max(depth + 1);
+            let mut c = value_start;
+            while c < value_end {
+                c = validate_at(buf, c, value_end, depth + 1, report)?;
+            }
+        }
+        OBJECT => {
+            report.depth = report.depth.max(depth + 1);
+            let mut c = value_start;
+            while c < value_end {
+                let (key_type, _, _) = read_tag_within(buf, c, value_end)?;
+                if key_type != STRING {
+                    return Err(BipfError::InvalidTag {
+                        offset: c,
+                        tag: key_type,
+                    });
+                }
+                c = validate_at(buf, c, value_end, depth + 1, report)?;
+                if c == value_end {
+                    return Err(BipfError::Truncated { offset: c });
+                }
+                c = validate_at(buf, c, value_end, depth + 1, report)?;
+            }
+        }
+        _ => {
+            return Err(BipfError::InvalidTag {
+                offset: start,
+                tag: field_type,
+            })
+        }
+    }
+
+    Ok(value_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::error::*;
+    use crate::validate::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_report() {
+        let buf = json!({"a": [1, "two", null, true, 0.5], "b": {"c": -1}})
+            .to_bipf()
+            .unwrap();
+
+        assert_eq!(
+            validate(&buf).unwrap(),
+            ValidationReport {
+                values: 12,
+                depth: 2
+            }
+        );
+        assert_eq!(
+            validate(&json!(1).to_bipf().unwrap()).unwrap(),
+            ValidationReport {
+                values: 1,
+                depth: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_malformed() {
+        let mut buf = json!({"a": [1]}).to_bipf().unwrap();
+        for end in 0..buf.len() {
+            assert_eq!(validate(&buf[..end]).is_err(), true);
+        }
+
+        // inner array claims 6 bytes inside an outer array of 5
+        assert!(matches!(
+            validate(&[0x2c, 0x34, 0x22, 0x01, 0x00, 0x00, 0x00, 0x00]),
+            Err(BipfError::LengthOverflow { offset: 1 })
+        ));
+        // object key that is an int
+        assert!(matches!(
+            validate(&[0x35, 0x22, 0x01, 0x00, 0x00, 0x00, 0x06]),
+            Err(BipfError::InvalidTag { offset: 1, tag: 2 })
+        ));
+        // object key without a value
+        assert!(matches!(
+            validate(&[0x15, 0x08, 0x61]),
+            Err(BipfError::Truncated { offset: 3 })
+        ));
+        assert!(matches!(
+            validate(&[0x0e, 0x05]),
+            Err(BipfError::InvalidBoolNull { offset: 1 })
+        ));
+        assert!(matches!(
+            validate(&[0x18, 0x61, 0xff, 0x62]),
+            Err(BipfError::InvalidUtf8 { offset: 2 })
+        ));
+        assert!(matches!(
+            validate(&[0x1a, 0x01, 0x00, 0x00]),
+            Err(BipfError::InvalidLength { offset: 0 })
+        ));
+
+        buf.push(0x06);
+        assert!(matches!(
+            validate(&buf),
+            Err(BipfError::TrailingBytes { offset: _ })
+        ));
+    }
+}
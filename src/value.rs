@@ -0,0 +1,672 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::*;
+use indexmap::IndexMap;
+use integer_encoding::VarInt;
+use serde_json::{Map, Value};
+use std::io::Write;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// An owned value covering every wire type, including `BUFFER` which JSON cannot express.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BipfValue {
+    Null,
+    /// The JS `undefined`, a boolnull of its own on the wire.
+    Undefined,
+    Bool(bool),
+    Int(i32),
+    Double(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Array(Vec<BipfValue>),
+    Object(IndexMap<String, BipfValue>),
+    /// An extension value as its subtype and data, whether or not the subtype is known.
+    Extension(u64, Vec<u8>),
+}
+
+/// How `BUFFER` values are represented as JSON.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BytesMapping {
+    /// `{"type": "Buffer", "data": [...]}`, which is what `JSON.stringify` does to a Node Buffer.
+    /// Such objects are turned back into bytes by `BipfValue::from_json`.
+    NodeBuffer,
+    /// A plain array of byte values. This is lossy, those arrays come back as arrays.
+    Array,
+    /// A standard, padded base64 string. Lossy like `Array`.
+    Base64,
+    /// A lowercase hex string. Lossy like `Array`.
+    Hex,
+    /// Refuse to convert bytes.
+    Error,
+}
+
+/// How extension values are represented as JSON.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExtensionMapping {
+    /// `{"type": "Extension", "subtype": n, "data": [...]}`. This is lossy, such objects come
+    /// back as plain objects, so that ordinary data of that shape is left alone.
+    Object,
+    /// The same object, turned back into an extension by `BipfValue::from_json`.
+    TaggedObject,
+    /// Refuse to convert extensions.
+    Error,
+}
+
+/// What to do with NaN and the infinities, which JS numbers can hold but JSON cannot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonFiniteMapping {
+    /// Replace them with null, like `JSON.stringify`.
+    Null,
+    /// Keep them. As JSON they become the strings `"NaN"`, `"Infinity"` and `"-Infinity"`,
+    /// which `BipfValue::from_json` turns back into doubles.
+    Preserve,
+    /// Refuse to convert them.
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonOptions {
+    pub bytes: BytesMapping,
+    pub non_finite: NonFiniteMapping,
+    pub extensions: ExtensionMapping,
+}
+
+impl Default for JsonOptions {
+    fn default() -> Self {
+        JsonOptions {
+            bytes: BytesMapping::NodeBuffer,
+            non_finite: NonFiniteMapping::Null,
+            extensions: ExtensionMapping::Object,
+        }
+    }
+}
+
+impl Bipf for BipfValue {
+    fn to_bipf(&self) -> Result<Vec<u8>> {
+        self.to_bipf_with(&EncodeOptions::default())
+    }
+
+    fn encode_into(&self, buf: &mut [u8], start: usize) -> Result<usize> {
+        let options = EncodeOptions::default();
+        let (lengths, needed) = self.measure(&options)?;
+        check_space(buf, start, needed)?;
+        self.write(
+            &options,
+            &mut lengths.into_iter(),
+            &mut &mut buf[start..start + needed],
+        )?;
+        Ok(needed)
+    }
+}
+
+impl BipfValue {
+    /// Like `to_bipf`, with control over how NaN and the infinities are encoded.
+    ///
+    /// Ints are already in the i32 range, so the integer policy does not apply.
+    pub fn to_bipf_with(&self, options: &EncodeOptions) -> Result<Vec<u8>> {
+        let (lengths, needed) = self.measure(options)?;
+        let mut buf = Vec::with_capacity(needed);
+        self.write(options, &mut lengths.into_iter(), &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Exact size of the value once encoded, tag included, computed without encoding it.
+    pub fn encoding_length(&self) -> usize {
+        let len = self.payload_length();
+        len + (len << TAG_SIZE).required_space()
+    }
+
+    fn payload_length(&self) -> usize {
+        match self {
+            BipfValue::Null => JSON_NULL_SIZE,
+            BipfValue::Bool(_) | BipfValue::Undefined => JSON_BOOL_SIZE,
+            BipfValue::Int(_) => JSON_INT_SIZE,
+            BipfValue::Double(_) => JSON_DOUBLE_SIZE,
+            BipfValue::String(s) => s.len(),
+            BipfValue::Bytes(b) => b.len(),
+            BipfValue::Extension(subtype, data) => extension_length(*subtype, data),
+            BipfValue::Array(v) => v.iter().map(BipfValue::encoding_length).sum(),
+            BipfValue::Object(o) => o
+                .iter()
+                .map(|(k, v)| {
+                    k.len() + (k.len() << TAG_SIZE).required_space() + v.encoding_length()
+                })
+                .sum(),
+        }
+    }
+
+    /// Payload lengths of the containers in the value, in the order `write` meets them, and
+    /// the size of the whole encoded value.
+    fn measure(&self, options: &EncodeOptions) -> Result<(Vec<usize>, usize)> {
+        let mut lengths = Vec::new();
+        let len = self.container_lengths(options, &mut lengths)?;
+        Ok((lengths, tagged_length(len)))
+    }
+
+    /// Payload length of the value, pushing those of the containers in it to `lengths`.
+    fn container_lengths(
+        &self,
+        options: &EncodeOptions,
+        lengths: &mut Vec<usize>,
+    ) -> Result<usize> {
+        let i = lengths.len();
+        let mut len = 0;
+        match self {
+            BipfValue::Double(d) => {
+                return match encode_double(*d, options.non_finite)? {
+                    Some(_) => Ok(JSON_DOUBLE_SIZE),
+                    None => Ok(JSON_NULL_SIZE),
+                }
+            }
+            BipfValue::Array(v) => {
+                lengths.push(0);
+                for x in v {
+                    len += tagged_length(x.container_lengths(options, lengths)?);
+                }
+            }
+            BipfValue::Object(o) => {
+                lengths.push(0);
+                for (k, v) in o {
+                    len += tagged_length(k.len());
+                    len += tagged_length(v.container_lengths(options, lengths)?);
+                }
+            }
+            _ => return Ok(self.payload_length()),
+        };
+        lengths[i] = len;
+        Ok(len)
+    }
+
+    fn write<W: Write>(
+        &self,
+        options: &EncodeOptions,
+        lengths: &mut std::vec::IntoIter<usize>,
+        out: &mut W,
+    ) -> Result<()> {
+        match self {
+            BipfValue::Null => write_tag_to(out, BOOLNULL, JSON_NULL_SIZE)?,
+            BipfValue::Bool(b) => {
+                write_tag_to(out, BOOLNULL, JSON_BOOL_SIZE)?;
+                out.write_all(&[*b as u8])?
+            }
+            BipfValue::Undefined => {
+                write_tag_to(out, BOOLNULL, JSON_BOOL_SIZE)?;
+                out.write_all(&[UNDEFINED])?
+            }
+            BipfValue::Int(i) => {
+                write_tag_to(out, INT, JSON_INT_SIZE)?;
+                out.write_all(&i.to_le_bytes())?
+            }
+            BipfValue::Double(d) => match encode_double(*d, options.non_finite)? {
+                Some(d) => {
+                    write_tag_to(out, DOUBLE, JSON_DOUBLE_SIZE)?;
+                    out.write_all(&d.to_le_bytes())?
+                }
+                None => write_tag_to(out, BOOLNULL, JSON_NULL_SIZE)?,
+            },
+            BipfValue::String(s) => {
+                write_tag_to(out, STRING, s.len())?;
+                out.write_all(s.as_bytes())?
+            }
+            BipfValue::Bytes(b) => {
+                write_tag_to(out, BUFFER, b.len())?;
+                out.write_all(b)?
+            }
+            BipfValue::Extension(subtype, data) => {
+                write_tag_to(out, EXTENSION, extension_length(*subtype, data))?;
+                out.write_all(&subtype.encode_var_vec())?;
+                out.write_all(data)?
+            }
+            BipfValue::Array(v) => {
+                write_tag_to(out, ARRAY, next_length(lengths))?;
+                for x in v {
+                    x.write(options, lengths, out)?;
+                }
+            }
+            BipfValue::Object(o) => {
+                write_tag_to(out, OBJECT, next_length(lengths))?;
+                for (k, v) in o {
+                    write_tag_to(out, STRING, k.len())?;
+                    out.write_all(k.as_bytes())?;
+                    v.write(options, lengths, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self, options: &JsonOptions) -> Result<Value> {
+        Ok(match self {
+            BipfValue::Null | BipfValue::Undefined => Value::Null,
+            BipfValue::Bool(b) => Value::Bool(*b),
+            BipfValue::Int(i) => Value::from(*i),
+            BipfValue::Double(d) => double_to_json(*d, options.non_finite)?,
+            BipfValue::String(s) => Value::String(s.clone()),
+            BipfValue::Bytes(b) => bytes_to_json(b, options.bytes)?,
+            BipfValue::Extension(subtype, data) => {
+                extension_json(*subtype, data, options.extensions)?
+            }
+            BipfValue::Array(v) => Value::Array(
+                v.iter()
+                    .map(|x| x.to_json(options))
+                    .collect::<Result<Vec<Value>>>()?,
+            ),
+            BipfValue::Object(o) => {
+                let mut map = Map::with_capacity(o.len());
+                for (k, v) in o {
+                    map.insert(k.clone(), v.to_json(options)?);
+                }
+                Value::Object(map)
+            }
+        })
+    }
+
+    pub fn from_json(value: &Value, options: &JsonOptions) -> BipfValue {
+        match value {
+            Value::Null => BipfValue::Null,
+            Value::Bool(b) => BipfValue::Bool(*b),
+            Value::Number(n) => match n.as_i64().map(i32::try_from) {
+                Some(Ok(i)) => BipfValue::Int(i),
+                _ => BipfValue::Double(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Value::String(s) => match (options.non_finite, non_finite_from_str(s)) {
+                (NonFiniteMapping::Preserve, Some(d)) => BipfValue::Double(d),
+                _ => BipfValue::String(s.clone()),
+            },
+            Value::Array(v) => {
+                BipfValue::Array(v.iter().map(|x| BipfValue::from_json(x, options)).collect())
+            }
+            Value::Object(o) => {
+                let extension = match options.extensions {
+                    ExtensionMapping::TaggedObject => extension_from_json(o),
+                    _ => None,
+                };
+                match (options.bytes, node_buffer_data(o), extension) {
+                    (BytesMapping::NodeBuffer, Some(bytes), _) => BipfValue::Bytes(bytes),
+                    (_, _, Some((subtype, data))) => BipfValue::Extension(subtype, data),
+                    _ => BipfValue::Object(
+                        o.iter()
+                            .map(|(k, v)| (k.clone(), BipfValue::from_json(v, options)))
+                            .collect(),
+                    ),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn bytes_to_json(bytes: &[u8], mapping: BytesMapping) -> Result<Value> {
+    let data = || Value::Array(bytes.iter().map(|b| Value::from(*b)).collect());
+    match mapping {
+        BytesMapping::NodeBuffer => {
+            let mut map = Map::with_capacity(2);
+            map.insert(String::from("type"), Value::from("Buffer"));
+            map.insert(String::from("data"), data());
+            Ok(Value::Object(map))
+        }
+        BytesMapping::Array => Ok(data()),
+        BytesMapping::Base64 => Ok(Value::String(base64(bytes))),
+        BytesMapping::Hex => Ok(Value::String(hex(bytes))),
+        BytesMapping::Error => Err(BipfError::Message(String::from(
+            "buffers cannot be converted to JSON",
+        ))),
+    }
+}
+
+pub(crate) fn double_to_json(d: f64, mapping: NonFiniteMapping) -> Result<Value> {
+    if d.is_finite() {
+        return Ok(Value::from(d));
+    }
+    match mapping {
+        NonFiniteMapping::Null => Ok(Value::Null),
+        // Spelled like JS does, Rust would print "inf"
+        NonFiniteMapping::Preserve if d.is_nan() => Ok(Value::from("NaN")),
+        NonFiniteMapping::Preserve if d > 0.0 => Ok(Value::from("Infinity")),
+        NonFiniteMapping::Preserve => Ok(Value::from("-Infinity")),
+        NonFiniteMapping::Error => Err(non_finite_error(d)),
+    }
+}
+
+pub(crate) fn extension_json(
+    subtype: u64,
+    data: &[u8],
+    mapping: ExtensionMapping,
+) -> Result<Value> {
+    match mapping {
+        ExtensionMapping::Object | ExtensionMapping::TaggedObject => {
+            Ok(extension_to_json(subtype, data))
+        }
+        ExtensionMapping::Error => Err(BipfError::Message(String::from(
+            "extensions cannot be converted to JSON",
+        ))),
+    }
+}
+
+/// The double to write for `d` under `mapping`, `None` to write null instead.
+pub(crate) fn encode_double(d: f64, mapping: NonFiniteMapping) -> Result<Option<f64>> {
+    match mapping {
+        _ if d.is_finite() => Ok(Some(d)),
+        NonFiniteMapping::Preserve => Ok(Some(d)),
+        NonFiniteMapping::Null => Ok(None),
+        NonFiniteMapping::Error => Err(non_finite_error(d)),
+    }
+}
+
+pub(crate) fn non_finite_error(d: f64) -> BipfError {
+    BipfError::Message(format!("non-finite double {} is not allowed", d))
+}
+
+/// Recognizes the strings produced by `double_to_json` for non-finite doubles.
+fn non_finite_from_str(s: &str) -> Option<f64> {
+    match s {
+        "NaN" => Some(f64::NAN),
+        "Infinity" => Some(f64::INFINITY),
+        "-Infinity" => Some(f64::NEG_INFINITY),
+        _ => None,
+    }
+}
+
+/// Decodes the value starting at `start` into JSON, with control over the lossy conversions.
+pub fn decode_with(buf: &[u8], start: usize, options: &JsonOptions) -> Result<Value> {
+    decode_value(buf, start)?.to_json(options)
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Recognizes the `{"type": "Buffer", "data": [...]}` form produced by `bytes_to_json`.
+fn node_buffer_data(o: &Map<String, Value>) -> Option<Vec<u8>> {
+    if o.len() != 2 || o.get("type")?.as_str()? != "Buffer" {
+        return None;
+    }
+    o.get("data")?
+        .as_array()?
+        .iter()
+        .map(|b| u8::try_from(b.as_u64()?).ok())
+        .collect()
+}
+
+/// Decodes the value starting at `start` into a `BipfValue`, buffers included.
+pub fn decode_value(buf: &[u8], start: usize) -> Result<BipfValue> {
+    decode_value_at(buf, start, buf.len(), 0)
+}
+
+fn decode_value_at(buf: &[u8], start: usize, end: usize, depth: usize) -> Result<BipfValue> {
+    let (field_type, len, bytes) = read_tag_within(buf, start, end)?;
+    let value_start = start + bytes;
+    let payload = &buf[value_start..value_start + len];
+
+    Ok(match field_type {
+        STRING => match std::str::from_utf8(payload) {
+            Ok(s) => BipfValue::String(String::from(s)),
+            Err(e) => {
+                return Err(BipfError::InvalidUtf8 {
+                    offset: value_start + e.valid_up_to(),
+                })
+            }
+        },
+        BUFFER => BipfValue::Bytes(payload.to_vec()),
+        EXTENSION => {
+            let (subtype, data) = split_extension(buf, value_start, len)?;
+            BipfValue::Extension(subtype, data.to_vec())
+        }
+        INT => match payload.try_into() {
+            Ok(b) => BipfValue::Int(i32::from_le_bytes(b)),
+            Err(_) => return Err(BipfError::InvalidLength { offset: start }),
+        },
+        DOUBLE => match payload.try_into() {
+            Ok(b) => BipfValue::Double(f64::from_le_bytes(b)),
+            Err(_) => return Err(BipfError::InvalidLength { offset: start }),
+        },
+        BOOLNULL => match payload {
+            [] => BipfValue::Null,
+            [UNDEFINED] => BipfValue::Undefined,
+            [b] if *b <= 1 => BipfValue::Bool(*b == 1),
+            _ => {
+                return Err(BipfError::InvalidBoolNull {
+                    offset: value_start,
+                })
+            }
+        },
+        ARRAY | OBJECT if depth >= MAX_DEPTH => {
+            return Err(BipfError::DepthExceeded { offset: start })
+        }
+        ARRAY => {
+            let end = value_start + len;
+            let mut c = value_start;
+            let mut vec = Vec::new();
+            while c < end {
+                vec.push(decode_value_at(buf, c, end, depth + 1)?);
+                c += next_offset(buf, c)?;
+            }
+            BipfValue::Array(vec)
+        }
+        OBJECT => {
+            let end = value_start + len;
+            let mut c = value_start;
+            let mut map = IndexMap::new();
+            while c < end {
+                let key = match decode_value_at(buf, c, end, depth + 1)? {
+                    BipfValue::String(key) => key,
+                    _ => {
+                        return Err(BipfError::InvalidTag {
+                            offset: c,
+                            tag: read_tag(buf, c)?.0,
+                        })
+                    }
+                };
+                c += next_offset(buf, c)?;
+                let value = decode_value_at(buf, c, end, depth + 1)?;
+                c += next_offset(buf, c)?;
+                map.insert(key, value);
+            }
+            BipfValue::Object(map)
+        }
+        _ => {
+            return Err(BipfError::InvalidTag {
+                offset: start,
+                tag: field_type,
+            })
+        }
+    })
+}
+
+/// Size of the value at `start`, tag included.
+fn next_offset(buf: &[u8], start: usize) -> Result<usize> {
+    let (_, len, bytes) = read_tag(buf, start)?;
+    Ok(bytes + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::value::*;
+    use serde_json::json;
+
+    fn all_types() -> BipfValue {
+        BipfValue::Array(vec![
+            BipfValue::String(String::from("hello")),
+            BipfValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+            BipfValue::Int(-1),
+            BipfValue::Double(0.5),
+            BipfValue::Object(IndexMap::from([(
+                String::from("foo"),
+                BipfValue::Bool(true),
+            )])),
+            BipfValue::Null,
+            BipfValue::Undefined,
+        ])
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let buf = all_types().to_bipf().unwrap();
+
+        assert_eq!(decode_value(&buf, 0).unwrap(), all_types());
+    }
+
+    #[test]
+    fn test_value_undefined() {
+        let buf = BipfValue::Undefined.to_bipf().unwrap();
+
+        assert_eq!(buf, vec![0x0e, UNDEFINED]);
+        assert_eq!(decode_rec(&buf, 0).unwrap(), json!(null));
+        assert_eq!(
+            BipfValue::Undefined
+                .to_json(&JsonOptions::default())
+                .unwrap(),
+            json!(null)
+        );
+    }
+
+    #[test]
+    fn test_value_encoding_length() {
+        let value = BipfValue::Array(vec![all_types(), BipfValue::Bytes(vec![0; 300])]);
+
+        assert_eq!(value.encoding_length(), value.to_bipf().unwrap().len());
+        assert_eq!(
+            all_types().encoding_length(),
+            all_types().to_bipf().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_value_decodes_node_buffer() {
+        // bipf.encode([-1, {foo: true}, Buffer.from('deadbeef', 'hex')]) from the JS library
+        let buf: Vec<u8> = vec![
+            0x8c, 0x01, 0x22, 0xff, 0xff, 0xff, 0xff, 0x35, 0x18, 0x66, 0x6f, 0x6f, 0x0e, 0x01,
+            0x21, 0xde, 0xad, 0xbe, 0xef,
+        ];
+        let value = decode_value(&buf, 0).unwrap();
+
+        assert_eq!(
+            value.to_json(&JsonOptions::default()).unwrap(),
+            json!([-1, {"foo": true}, {"type": "Buffer", "data": [222, 173, 190, 239]}])
+        );
+        assert_eq!(
+            decode(&buf).unwrap(),
+            value.to_json(&JsonOptions::default()).unwrap()
+        );
+        assert_eq!(value.to_bipf().unwrap(), buf);
+    }
+
+    #[test]
+    fn test_value_json_bytes_mapping() {
+        let node = JsonOptions::default();
+        let array = JsonOptions {
+            bytes: BytesMapping::Array,
+            ..JsonOptions::default()
+        };
+        let error = JsonOptions {
+            bytes: BytesMapping::Error,
+            ..JsonOptions::default()
+        };
+        let bytes = BipfValue::Bytes(vec![1, 2]);
+
+        assert_eq!(bytes.to_json(&array).unwrap(), json!([1, 2]));
+        assert_eq!(bytes.to_json(&error).is_err(), true);
+        let as_json = bytes.to_json(&node).unwrap();
+        assert_eq!(as_json, json!({"type": "Buffer", "data": [1, 2]}));
+        assert_eq!(BipfValue::from_json(&as_json, &node), bytes);
+        assert_eq!(
+            BipfValue::from_json(&as_json, &array),
+            BipfValue::Object(IndexMap::from([
+                (
+                    String::from("type"),
+                    BipfValue::String(String::from("Buffer"))
+                ),
+                (
+                    String::from("data"),
+                    BipfValue::Array(vec![BipfValue::Int(1), BipfValue::Int(2)])
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_value_encoding_matches_json() {
+        let input = json!({"a": [1, "two", null, true, 0.5], "b": {"c": -1}});
+        let value = BipfValue::from_json(&input, &JsonOptions::default());
+
+        assert_eq!(value.to_bipf().unwrap(), input.to_bipf().unwrap());
+    }
+
+    #[test]
+    fn test_value_non_finite_doubles() {
+        let preserve = JsonOptions {
+            non_finite: NonFiniteMapping::Preserve,
+            ..JsonOptions::default()
+        };
+        let error = JsonOptions {
+            non_finite: NonFiniteMapping::Error,
+            ..JsonOptions::default()
+        };
+        let value = BipfValue::Array(vec![
+            BipfValue::Double(f64::NAN),
+            BipfValue::Double(f64::INFINITY),
+            BipfValue::Double(f64::NEG_INFINITY),
+            BipfValue::Double(0.5),
+        ]);
+        let buf = value.to_bipf().unwrap();
+
+        // NaN != NaN, so compare the encodings
+        assert_eq!(decode_value(&buf, 0).unwrap().to_bipf().unwrap(), buf);
+
+        assert_eq!(
+            decode_with(&buf, 0, &JsonOptions::default()).unwrap(),
+            json!([null, null, null, 0.5])
+        );
+        assert_eq!(decode_rec(&buf, 0).unwrap(), json!([null, null, null, 0.5]));
+        assert!(decode_with(&buf, 0, &error).is_err());
+
+        let as_json = decode_with(&buf, 0, &preserve).unwrap();
+        assert_eq!(as_json, json!(["NaN", "Infinity", "-Infinity", 0.5]));
+        let back = BipfValue::from_json(&as_json, &preserve);
+        assert_eq!(back.to_bipf().unwrap(), buf);
+        assert_eq!(
+            BipfValue::from_json(&json!("NaN"), &JsonOptions::default()),
+            BipfValue::String(String::from("NaN"))
+        );
+
+        let null = EncodeOptions {
+            non_finite: NonFiniteMapping::Null,
+            ..EncodeOptions::default()
+        };
+        let refuse = EncodeOptions {
+            non_finite: NonFiniteMapping::Error,
+            ..EncodeOptions::default()
+        };
+        assert_eq!(
+            value.to_bipf_with(&null).unwrap(),
+            json!([null, null, null, 0.5]).to_bipf().unwrap()
+        );
+        assert!(value.to_bipf_with(&refuse).is_err());
+        assert_eq!(
+            BipfValue::Double(0.5).to_bipf_with(&refuse).unwrap(),
+            json!(0.5).to_bipf().unwrap()
+        );
+    }
+}
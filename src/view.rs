@@ -0,0 +1,227 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::split_extension;
+
+/// A borrowed, zero-copy view over a single encoded value.
+///
+/// Accessors read straight from the underlying bytes and never build a `serde_json::Value`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BipfRef<'a> {
+    buf: &'a [u8],
+    start: usize,
+    field_type: usize,
+    len: usize,
+    value_start: usize,
+}
+
+impl<'a> BipfRef<'a> {
+    /// Creates a view over the value whose tag starts at `start`.
+    pub fn new(buf: &'a [u8], start: usize) -> Result<BipfRef<'a>, BipfError> {
+        let (field_type, len, bytes) = read_tag(buf, start)?;
+        Ok(BipfRef {
+            buf,
+            start,
+            field_type,
+            len,
+            value_start: start + bytes,
+        })
+    }
+
+    /// The type tag, one of `STRING`, `BUFFER`, `INT`, `DOUBLE`, `ARRAY`, `OBJECT`, `BOOLNULL`
+    /// or `EXTENSION`.
+    pub fn field_type(&self) -> usize {
+        self.field_type
+    }
+
+    /// Length of the payload in bytes, not counting the tag.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offset of the tag in the underlying buffer.
+    pub fn offset(&self) -> usize {
+        self.start
+    }
+
+    /// Offset of the payload in the underlying buffer.
+    pub fn value_offset(&self) -> usize {
+        self.value_start
+    }
+
+    /// The whole encoded value, tag included.
+    pub fn raw(&self) -> &'a [u8] {
+        &self.buf[self.start..self.value_start + self.len]
+    }
+
+    /// The payload, without its tag.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.buf[self.value_start..self.value_start + self.len]
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.field_type == BOOLNULL && self.len == JSON_NULL_SIZE
+    }
+
+    pub fn is_undefined(&self) -> bool {
+        self.field_type == BOOLNULL && self.payload() == [UNDEFINED]
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match (self.field_type, self.payload()) {
+            (BOOLNULL, [0]) => Some(false),
+            (BOOLNULL, [1]) => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        if self.field_type == STRING {
+            std::str::from_utf8(self.payload()).ok()
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        if self.field_type == BUFFER {
+            Some(self.payload())
+        } else {
+            None
+        }
+    }
+
+    /// The subtype and data of an extension value.
+    pub fn as_extension(&self) -> Option<(u64, &'a [u8])> {
+        match self.field_type {
+            EXTENSION => split_extension(self.buf, self.value_start, self.len).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        if self.field_type == INT {
+            Some(i32::from_le_bytes(self.payload().try_into().ok()?))
+        } else {
+            None
+        }
+    }
+
+    /// Returns doubles as-is and widens ints, like `serde_json::Value::as_f64`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.field_type {
+            DOUBLE => Some(f64::from_le_bytes(self.payload().try_into().ok()?)),
+            INT => self.as_i32().map(f64::from),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` in an object without decoding any of its other fields.
+    pub fn get(&self, key: &str) -> Option<BipfRef<'a>> {
+        if self.field_type != OBJECT {
+            return None;
+        }
+
+        let target = key.as_bytes();
+        let payload = self.payload();
+        let mut c = 0;
+        while c < payload.len() {
+            let (key_type, key_len, key_bytes) = read_tag(payload, c).ok()?;
+            c += key_bytes;
+            let matched = key_type == STRING && &payload[c..c + key_len] == target;
+            c += key_len;
+
+            let value = BipfRef::new(payload, c).ok()?;
+            if matched {
+                return BipfRef::new(self.buf, self.value_start + c).ok();
+            }
+            c += value.raw().len();
+        }
+
+        None
+    }
+
+    /// Returns the `i`th element of an array.
+    pub fn index(&self, i: usize) -> Option<BipfRef<'a>> {
+        if self.field_type != ARRAY {
+            return None;
+        }
+
+        let payload = self.payload();
+        let mut c = 0;
+        let mut n = 0;
+        while c < payload.len() {
+            let value = BipfRef::new(payload, c).ok()?;
+            if n == i {
+                return BipfRef::new(self.buf, self.value_start + c).ok();
+            }
+            n += 1;
+            c += value.raw().len();
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::view::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_view_accessors() {
+        let buf = json!({
+            "name": "bipf",
+            "version": 2,
+            "ratio": 0.5,
+            "private": false,
+            "license": null,
+            "keywords": ["binary", "in-place"]
+        })
+        .to_bipf()
+        .unwrap();
+        let root = BipfRef::new(&buf, 0).unwrap();
+
+        assert_eq!(root.field_type(), OBJECT);
+        assert_eq!(root.raw().len(), buf.len());
+        assert_eq!(root.get("name").unwrap().as_str(), Some("bipf"));
+        assert_eq!(root.get("version").unwrap().as_i32(), Some(2));
+        assert_eq!(root.get("version").unwrap().as_f64(), Some(2.0));
+        assert_eq!(root.get("ratio").unwrap().as_f64(), Some(0.5));
+        assert_eq!(root.get("private").unwrap().as_bool(), Some(false));
+        assert_eq!(root.get("license").unwrap().is_null(), true);
+        assert_eq!(root.get("missing"), None);
+        assert_eq!(root.get("name").unwrap().as_i32(), None);
+
+        let keywords = root.get("keywords").unwrap();
+        assert_eq!(keywords.index(1).unwrap().as_str(), Some("in-place"));
+        assert_eq!(keywords.index(2), None);
+        assert_eq!(keywords.get("name"), None);
+    }
+
+    #[test]
+    fn test_view_offsets_match_seek_key() {
+        let buf = json!({"hello": "unnecessary", "dependencies": { "rust": "v2.0.1" }})
+            .to_bipf()
+            .unwrap();
+        let view = BipfRef::new(&buf, 0).unwrap().get("dependencies").unwrap();
+
+        assert_eq!(
+            Some(view.offset()),
+            seek_key(&buf, Some(0), String::from("dependencies")).unwrap()
+        );
+        assert_eq!(view.get("rust").unwrap().as_str(), Some("v2.0.1"));
+    }
+
+    #[test]
+    fn test_view_truncated() {
+        let buf = json!({"hello": "world"}).to_bipf().unwrap();
+
+        assert_eq!(BipfRef::new(&buf[..buf.len() - 1], 0).is_err(), true);
+        assert_eq!(BipfRef::new(&buf, buf.len()).is_err(), true);
+    }
+}
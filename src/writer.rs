@@ -0,0 +1,248 @@
+use crate::bipf::*;
+use crate::error::BipfError;
+use crate::extension::{encode_extension, Extension};
+use std::io::Write;
+
+type Result<T> = std::result::Result<T, BipfError>;
+
+/// Builds an encoded document value by value, without an intermediate tree.
+///
+/// Containers are written with a placeholder tag that is patched once they are closed, so
+/// their lengths need not be known upfront. Bytes are handed to the inner writer each time a
+/// top-level value is complete.
+pub struct BipfWriter<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+    stack: Vec<Container>,
+}
+
+struct Container {
+    field_type: usize,
+    start: usize,
+    /// In an object, whether the next item must be a key.
+    expects_key: bool,
+}
+
+impl<W: Write> BipfWriter<W> {
+    pub fn new(out: W) -> BipfWriter<W> {
+        BipfWriter {
+            out,
+            buf: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Number of containers opened and not closed yet.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn begin_object(&mut self) -> Result<&mut Self> {
+        self.begin(OBJECT)
+    }
+
+    pub fn end_object(&mut self) -> Result<&mut Self> {
+        self.end(OBJECT)
+    }
+
+    pub fn begin_array(&mut self) -> Result<&mut Self> {
+        self.begin(ARRAY)
+    }
+
+    pub fn end_array(&mut self) -> Result<&mut Self> {
+        self.end(ARRAY)
+    }
+
+    /// Writes the key of the next object entry.
+    pub fn key(&mut self, key: &str) -> Result<&mut Self> {
+        match self.stack.last_mut() {
+            Some(top) if top.field_type == OBJECT && top.expects_key => {
+                top.expects_key = false;
+            }
+            _ => return Err(misuse("key written outside of an object or twice in a row")),
+        }
+        write_tag(&mut self.buf, STRING, key.len());
+        self.buf.extend_from_slice(key.as_bytes());
+        Ok(self)
+    }
+
+    pub fn string(&mut self, s: &str) -> Result<&mut Self> {
+        self.scalar(STRING, s.as_bytes())
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) -> Result<&mut Self> {
+        self.scalar(BUFFER, b)
+    }
+
+    pub fn int(&mut self, i: i32) -> Result<&mut Self> {
+        self.scalar(INT, &i.to_le_bytes())
+    }
+
+    pub fn double(&mut self, d: f64) -> Result<&mut Self> {
+        self.scalar(DOUBLE, &d.to_le_bytes())
+    }
+
+    pub fn bool(&mut self, b: bool) -> Result<&mut Self> {
+        self.scalar(BOOLNULL, &[b as u8])
+    }
+
+    pub fn null(&mut self) -> Result<&mut Self> {
+        self.scalar(BOOLNULL, &[])
+    }
+
+    pub fn extension<T: Extension>(&mut self, value: &T) -> Result<&mut Self> {
+        self.raw(&encode_extension(value))
+    }
+
+    /// Writes an already encoded value as is.
+    pub fn raw(&mut self, encoded: &[u8]) -> Result<&mut Self> {
+        self.before_value()?;
+        self.buf.extend_from_slice(encoded);
+        self.after_value()
+    }
+
+    /// Flushes the inner writer. Fails if a container is still open.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.stack.is_empty() {
+            return Err(misuse("cannot flush with unclosed containers"));
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Flushes and returns the inner writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush()?;
+        Ok(self.out)
+    }
+
+    fn scalar(&mut self, field_type: usize, payload: &[u8]) -> Result<&mut Self> {
+        self.before_value()?;
+        write_tag(&mut self.buf, field_type, payload.len());
+        self.buf.extend_from_slice(payload);
+        self.after_value()
+    }
+
+    fn begin(&mut self, field_type: usize) -> Result<&mut Self> {
+        self.before_value()?;
+        let start = begin_container(&mut self.buf);
+        self.stack.push(Container {
+            field_type,
+            start,
+            expects_key: true,
+        });
+        Ok(self)
+    }
+
+    fn end(&mut self, field_type: usize) -> Result<&mut Self> {
+        match self.stack.last() {
+            Some(top) if top.field_type == field_type && top.expects_key => {}
+            Some(top) if top.field_type == field_type => {
+                return Err(misuse("object closed after a key with no value"))
+            }
+            _ => return Err(misuse("closing a container that is not open")),
+        }
+        if let Some(top) = self.stack.pop() {
+            end_container(&mut self.buf, top.start, field_type);
+        }
+        self.after_value()
+    }
+
+    fn before_value(&self) -> Result<()> {
+        match self.stack.last() {
+            Some(top) if top.field_type == OBJECT && top.expects_key => {
+                Err(misuse("object value written without a key"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn after_value(&mut self) -> Result<&mut Self> {
+        match self.stack.last_mut() {
+            Some(top) => top.expects_key = true,
+            None => {
+                self.out.write_all(&self.buf)?;
+                self.buf.clear();
+            }
+        }
+        Ok(self)
+    }
+}
+
+fn misuse(message: &str) -> BipfError {
+    BipfError::Message(String::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bipf::*;
+    use crate::writer::*;
+    use serde_json::json;
+
+    fn write_sample(w: &mut BipfWriter<Vec<u8>>, long: &str) -> Result<()> {
+        w.begin_object()?
+            .key("a")?
+            .begin_array()?
+            .int(1)?
+            .string("two")?
+            .null()?
+            .bool(true)?
+            .double(0.5)?
+            .end_array()?
+            .key("long")?
+            .begin_object()?
+            .key("s")?
+            .string(long)?
+            .end_object()?
+            .key("empty")?
+            .begin_array()?
+            .end_array()?
+            .end_object()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer_matches_value_encoding() {
+        let long = "x".repeat(300);
+        let mut w = BipfWriter::new(Vec::new());
+        write_sample(&mut w, &long).unwrap();
+        let buf = w.into_inner().unwrap();
+
+        let expected = json!({"a": [1, "two", null, true, 0.5], "long": {"s": long}, "empty": []});
+        assert_eq!(buf, expected.to_bipf().unwrap());
+    }
+
+    #[test]
+    fn test_writer_streams_complete_values() {
+        let mut out = Vec::new();
+        {
+            let mut w = BipfWriter::new(&mut out);
+            w.begin_array().unwrap().bytes(&[1, 2, 3]).unwrap();
+            assert_eq!(w.depth(), 1);
+            assert_eq!(w.flush().is_err(), true);
+            w.end_array().unwrap().int(7).unwrap();
+            w.flush().unwrap();
+        }
+
+        let first = decode_rec(&out, 0).unwrap();
+        assert_eq!(first, json!([{"type": "Buffer", "data": [1, 2, 3]}]));
+        assert_eq!(decode_rec(&out, out.len() - 5).unwrap(), json!(7));
+    }
+
+    #[test]
+    fn test_writer_misuse() {
+        let mut w = BipfWriter::new(Vec::new());
+        assert_eq!(w.key("a").is_err(), true);
+        assert_eq!(w.end_array().is_err(), true);
+
+        let mut w = BipfWriter::new(Vec::new());
+        w.begin_object().unwrap();
+        assert_eq!(w.int(1).is_err(), true);
+        w.key("a").unwrap();
+        assert_eq!(w.key("b").is_err(), true);
+        assert_eq!(w.end_object().is_err(), true);
+        assert_eq!(w.end_array().is_err(), true);
+        w.int(1).unwrap().end_object().unwrap();
+        assert_eq!(w.into_inner().unwrap(), json!({"a": 1}).to_bipf().unwrap());
+    }
+}
@@ -36,7 +36,7 @@ testEncodeDecode(-1)
 testEncodeDecode(true)
 testEncodeDecode(false)
 testEncodeDecode(null)
-// testEncodeDecode(undefined) // added undefined for compatibility with charwise
+testEncodeDecode(undefined) // added undefined for compatibility with charwise
 testEncodeDecode('')
 testEncodeDecode(Buffer.alloc(0))
 testEncodeDecode([])
@@ -48,6 +48,18 @@ testEncodeDecode({ foo: true })
 testEncodeDecode([-1, { foo: true }, Buffer.from('deadbeef', 'hex')])
 testEncodeDecode(pkg)
 testEncodeDecode(0.1)
+testEncodeDecode(2147483647)
+testEncodeDecode(4294967296)
+testEncodeDecode(Number.MAX_SAFE_INTEGER)
+
+tape('non-finite doubles round-trip', (t) => {
+  for (const value of [NaN, Infinity, -Infinity]) {
+    const encoded = bipf.encode(value)
+    t.equal(encoded[0], 0x43, 'encoded as a double')
+    t.ok(Object.is(bipf.decode(encoded, 0), value), String(value))
+  }
+  t.end()
+})
 
 tape('seekKey() on an object', (t) => {
   const objEncoded = bipfReference.allocAndEncode({ x: 10, y: 20 })
@@ -71,3 +83,55 @@ tape('seekKey() on an array', (t) => {
   t.equals(pointer, -1)
   t.end()
 })
+
+tape('seekPath() with an encoded path', (t) => {
+  const objEncoded = bipfReference.allocAndEncode({ x: { y: { z: 42 } } })
+  const path = bipfReference.allocAndEncode(['x', 'y', 'z'])
+  const pointer = bipf.seekPath(objEncoded, 0, path)
+  t.equals(pointer, bipfReference.seekPath(objEncoded, 0, path))
+  t.equals(bipf.decode(objEncoded, pointer), 42)
+  t.equals(bipf.seekPath(objEncoded, 0, bipfReference.allocAndEncode(['x', 'w'])), -1)
+  t.end()
+})
+
+tape('seekPath() with array indices', (t) => {
+  const objEncoded = bipfReference.allocAndEncode({ x: [10, { y: 20 }] })
+  const pointer = bipf.seekPath(objEncoded, 0, ['x', 1, 'y'])
+  t.equals(bipf.decode(objEncoded, pointer), 20)
+  t.equals(bipf.seekPath(objEncoded, 0, ['x', 2]), -1)
+  t.equals(bipf.seekPath(objEncoded, -1, ['x']), -1)
+  t.end()
+})
+
+tape('createSeekPath()', (t) => {
+  const seek = bipf.createSeekPath(['value', 'content', 'type'])
+  const post = bipfReference.allocAndEncode({ value: { content: { type: 'post' } } })
+  const other = bipfReference.allocAndEncode({ value: { content: { text: 'hi' } } })
+  t.equals(bipf.decode(post, seek(post, 0)), 'post')
+  t.equals(seek(other, 0), -1)
+  t.equals(seek(post, 0), bipfReference.createSeekPath(['value', 'content', 'type'])(post, 0))
+  t.end()
+})
+
+tape('encode() into a buffer at an offset', (t) => {
+  const value = { hello: ['world', 1, 0.5, null] }
+  const len = bipfReference.encodingLength(value)
+  const buf = Buffer.alloc(len + 4, 0xff)
+  t.equals(bipf.encode(value, buf, 2), len)
+  t.deepEqual(buf.slice(2, 2 + len), bipfReference.allocAndEncode(value))
+  t.deepEqual(bipf.decode(buf, 2), value)
+  t.throws(() => bipf.encode(value, buf, 5))
+  t.throws(() => bipf.encode({ data: buf.slice(0, 4) }, buf, 0))
+  t.end()
+})
+
+tape('decode() an extension value', (t) => {
+  // tag for a 3-byte extension payload: subtype 1, then 2 bytes of data
+  const buf = Buffer.from([0x1f, 0x01, 0xab, 0xcd])
+  t.deepEqual(bipf.decode(buf, 0), {
+    type: 'Extension',
+    subtype: 1,
+    data: Buffer.from([0xab, 0xcd])
+  })
+  t.end()
+})
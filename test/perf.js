@@ -11,13 +11,13 @@ var suite = new Benchmark.Suite;
 var dependencies = Buffer.from('dependencies')
 var varint = Buffer.from('varint')
 suite
-// .add(function() {
-//   bipf.encode(pkg)
-// }, { name: "neon.binary.encode"})
-// .add(function() {
-//   let buf = Buffer.allocUnsafe(binary.encodingLength(pkg))
-//   binary.encode(pkg, buf, 0)
-// }, { name: "binary.encode"})
+.add(function() {
+  bipf.encode(pkg)
+}, { name: "neon.binary.encode"})
+.add(function() {
+  let buf = Buffer.allocUnsafe(binary.encodingLength(pkg))
+  binary.encode(pkg, buf, 0)
+}, { name: "binary.encode"})
 .add(function() {
   bindgen.decode(encoded, 0)
 }, {name: "bindgen.binary.decode"})